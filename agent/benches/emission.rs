@@ -0,0 +1,178 @@
+//! Throughput of the body emission path over a multi-GB synthetic tree.
+//!
+//! Every file is pushed through the same read and compress loop twice: once
+//! framing bodies the way the agent used to, copying the compressor's output
+//! into a `FileBody` and the serialized message into its frame, and once with
+//! `frame_parts`, which hands the compressor's output on untouched. The real
+//! `build_stream` is then run over the tree for comparison, which also
+//! includes the default digests.
+//!
+//! ```text
+//! cargo bench -p agent --bench emission
+//! ```
+//!
+//! `EMISSION_BENCH_GIB` sets the size of the tree, 2 GiB by default.
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use agentfs::{
+    codec::{Compression, Encoder},
+    stream::{FRAME_HEADER_LEN, FRAME_MAGIC, Message, StreamOptions, build_stream, frame_parts},
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::TryStreamExt;
+use rkyv::{api::high::to_bytes_with_alloc, rancor, ser::allocator::Arena};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+const READ_SIZE: usize = 64 * 1024;
+const FILE_SIZE: usize = 16 * 1024 * 1024;
+
+fn main() {
+    let gib = env::var("EMISSION_BENCH_GIB")
+        .ok()
+        .and_then(|gib| gib.parse().ok())
+        .unwrap_or(2);
+
+    let tree = tempfile::tempdir().unwrap();
+    let files = synthetic_tree(tree.path(), gib * GIB);
+    let total = gib * GIB;
+
+    println!("{gib} GiB in {} files", files.len());
+
+    // Get the whole tree into the page cache before timing anything.
+    pipeline(&files, Compression::Store, copying_frame);
+
+    for compression in [Compression::Store, Compression::Lz4] {
+        let before = pipeline(&files, compression, copying_frame);
+        let after = pipeline(&files, compression, |data, arena| {
+            frame_parts(&Message::FileBody { data }, arena)
+        });
+
+        println!(
+            "{:>6}: copying {:>7.1} MiB/s, zero-copy {:>7.1} MiB/s",
+            compression.to_string(),
+            throughput(total, before),
+            throughput(total, after)
+        );
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    for compression in [Compression::Store, Compression::Lz4] {
+        let options = StreamOptions {
+            compression,
+            ..Default::default()
+        };
+        let roots = [tree.path().to_owned()].into_iter();
+
+        let start = Instant::now();
+        runtime
+            .block_on(build_stream(roots, options).try_for_each(|_| async { Ok(()) }))
+            .unwrap();
+
+        println!(
+            "{:>6}: build_stream {:>7.1} MiB/s",
+            compression.to_string(),
+            throughput(total, start.elapsed())
+        );
+    }
+}
+
+/// Files of half text and half noise, spread over a few directories.
+fn synthetic_tree(root: &Path, size: u64) -> Vec<PathBuf> {
+    let text =
+        "Oct 19 12:00:00 host sshd[4242]: Accepted publickey for root\n".repeat(FILE_SIZE / 128);
+    let mut state = 0x2545f491u32;
+    let noise: Vec<u8> = (0..FILE_SIZE - text.len())
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    (0..size / FILE_SIZE as u64)
+        .map(|index| {
+            let dir = root.join(format!("{:02}", index % 16));
+            fs::create_dir_all(&dir).unwrap();
+
+            let path = dir.join(format!("{index}.bin"));
+            let mut file = File::create(&path).unwrap();
+            file.write_all(text.as_bytes()).unwrap();
+            file.write_all(&noise).unwrap();
+
+            path
+        })
+        .collect()
+}
+
+/// Read, compress and frame every file, counting the framed bytes as they'd
+/// be handed to the socket.
+fn pipeline(
+    files: &[PathBuf],
+    compression: Compression,
+    frame: impl Fn(Bytes, &mut Arena) -> Vec<Bytes>,
+) -> Duration {
+    let mut arena = Arena::new();
+    let mut sent = 0;
+    let start = Instant::now();
+
+    for path in files {
+        let mut file = File::open(path).unwrap();
+        let mut buffer = BytesMut::new();
+        let mut encoder = Encoder::new(compression.codec_for(b""), compression).unwrap();
+
+        loop {
+            buffer.resize(READ_SIZE, 0);
+            let read = file.read(&mut buffer).unwrap();
+
+            if read == 0 {
+                break;
+            }
+
+            buffer.truncate(read);
+            let data = encoder.encode(buffer.split().freeze()).unwrap();
+            sent += frame(data, &mut arena)
+                .iter()
+                .map(Bytes::len)
+                .sum::<usize>();
+        }
+
+        let data = encoder.finish().unwrap();
+        sent += frame(data, &mut arena)
+            .iter()
+            .map(Bytes::len)
+            .sum::<usize>();
+    }
+
+    assert!(sent > 0);
+
+    start.elapsed()
+}
+
+/// How bodies used to be framed.
+fn copying_frame(data: Bytes, arena: &mut Arena) -> Vec<Bytes> {
+    let message = Message::FileBody {
+        data: Bytes::copy_from_slice(&data),
+    };
+    let payload = to_bytes_with_alloc::<_, rancor::Error>(&message, arena.acquire()).unwrap();
+    let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + payload.len());
+
+    frame.put_slice(&FRAME_MAGIC);
+    frame.put_u32_le(payload.len() as u32);
+    frame.put_u32_le(crc32fast::hash(&payload));
+    frame.put_slice(&payload);
+
+    vec![frame.freeze()]
+}
+
+fn throughput(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
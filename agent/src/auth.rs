@@ -0,0 +1,688 @@
+//! Who may use the web service, and what they may collect.
+//!
+//! Callers send `Authorization: Bearer <token>`, where the token is either
+//! one of the static tokens `Auth::new` reads, or an HS256 JSON Web Token
+//! signed with the service's key. A signed token names the caller in
+//! `sub`, stops working at `exp`, and carries its scope in `roots` and
+//! `patterns`. `mint` makes them, as can any JWT library.
+//!
+//! A scope narrows what's collected or hunted to the parts of the service's
+//! roots under its `roots`, and to the entries matching its `patterns`.
+//! Either being empty leaves that side unrestricted.
+//!
+//! Only admins can adjust the throttle and the known-file list, which every
+//! collection shares: callers with no scope at all, and those whose token
+//! claims `admin`.
+//!
+//! Every failed attempt is logged, and a peer that fails `MAX_FAILURES`
+//! times within `FAILURE_WINDOW` is turned away until the window has
+//! passed. Clients of a Unix socket can't be told apart, so they aren't
+//! turned away: one of them failing would shut out every other, and the
+//! socket's own permissions already decide who may connect.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Component, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{ConnectInfo, Request, State, connect_info::Connected},
+    http::{
+        StatusCode,
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    serve::IncomingStream,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::{mode::PatternSet, tls::TlsListener};
+
+/// How many failed attempts a peer gets within `FAILURE_WINDOW`.
+const MAX_FAILURES: u32 = 5;
+
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many peers' failures are remembered before forgetting those whose
+/// window has passed.
+const MAX_PEERS: usize = 4096;
+
+/// The only JWT header a signed token may have.
+const JWT_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// The shortest key signed tokens may be made with.
+pub const MIN_KEY_LEN: usize = 32;
+
+/// What a token may collect.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patterns: Vec<String>,
+    /// May change what every caller's collections are held to, whatever
+    /// they may collect themselves.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub admin: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Someone whose token checked out.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub name: String,
+    scope: Scope,
+    include: Option<Arc<PatternSet>>,
+}
+
+impl Caller {
+    pub fn new(name: String, scope: Scope) -> anyhow::Result<Self> {
+        if let Some(root) = scope
+            .roots
+            .iter()
+            .find(|root| root.components().any(|c| c == Component::ParentDir))
+        {
+            anyhow::bail!("`{}` can't be part of a scope", root.display());
+        }
+
+        let include = match scope.patterns.is_empty() {
+            true => None,
+            false => Some(Arc::new(PatternSet::new(scope.patterns.clone())?)),
+        };
+
+        Ok(Self {
+            name,
+            scope,
+            include,
+        })
+    }
+
+    /// Whoever calls a service that doesn't check tokens.
+    pub fn anyone() -> Self {
+        Self {
+            name: "anyone".to_owned(),
+            scope: Scope::default(),
+            include: None,
+        }
+    }
+
+    /// The parts of `roots` the caller may collect: those under one of their
+    /// scope's roots, or the scope's roots under them.
+    pub fn roots_within(&self, roots: &[PathBuf]) -> Vec<PathBuf> {
+        if self.scope.roots.is_empty() {
+            return roots.to_vec();
+        }
+
+        let mut within = Vec::new();
+
+        for root in roots {
+            for allowed in &self.scope.roots {
+                let narrowest = if allowed.starts_with(root) {
+                    allowed
+                } else if root.starts_with(allowed) {
+                    root
+                } else {
+                    continue;
+                };
+
+                if !within.contains(narrowest) {
+                    within.push(narrowest.clone());
+                }
+            }
+        }
+
+        within
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    /// Whether the caller may change settings every collection shares.
+    pub fn is_admin(&self) -> bool {
+        self.scope.admin || (self.scope.roots.is_empty() && self.scope.patterns.is_empty())
+    }
+
+    /// The patterns everything the caller collects must match, if they're
+    /// limited to some.
+    pub fn include(&self) -> Option<Arc<PatternSet>> {
+        self.include.clone()
+    }
+}
+
+/// Why a request was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Missing,
+    Malformed,
+    Unknown,
+    BadSignature,
+    Expired,
+    TooManyFailures,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Missing => "no bearer token",
+            Self::Malformed => "malformed token",
+            Self::Unknown => "unknown token",
+            Self::BadSignature => "bad token signature",
+            Self::Expired => "expired token",
+            Self::TooManyFailures => "too many failed attempts, try again later",
+        })
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        match self {
+            Self::TooManyFailures => {
+                (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+            }
+            _ => (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, "Bearer")],
+                self.to_string(),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// The claims of a signed token.
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    /// Seconds since the Unix epoch.
+    exp: u64,
+    #[serde(flatten)]
+    scope: Scope,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// The tokens the service accepts. The default accepts anyone, without a
+/// token.
+#[derive(Debug, Default)]
+pub struct Auth {
+    /// Static tokens by their BLAKE3 hash, so that looking one up takes no
+    /// time that depends on how close a wrong guess came.
+    tokens: HashMap<[u8; 32], Caller>,
+    key: Option<Vec<u8>>,
+    failures: Mutex<HashMap<Peer, Failures>>,
+}
+
+impl Auth {
+    /// Accept the static tokens in `tokens`, and those signed with `key`.
+    ///
+    /// Static tokens are one per line, as `<name> <token>`, followed by
+    /// any number of `root:<path>` and `pattern:<glob>` making up its
+    /// scope, and `admin` if a scoped token is an admin's all the same.
+    /// Empty lines and those starting with `#` are ignored.
+    pub fn new(tokens: Option<&str>, key: Option<Vec<u8>>) -> anyhow::Result<Self> {
+        if let Some(key) = &key {
+            anyhow::ensure!(
+                key.len() >= MIN_KEY_LEN,
+                "the token key must be at least {MIN_KEY_LEN} bytes"
+            );
+        }
+
+        let lines = tokens.unwrap_or_default().lines().enumerate();
+        let mut parsed = HashMap::new();
+
+        for (number, line) in lines.filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        }) {
+            let (hash, caller) = parse_token_line(line)
+                .with_context(|| format!("line {}: invalid token", number + 1))?;
+
+            anyhow::ensure!(
+                parsed.insert(hash, caller).is_none(),
+                "line {}: the same token is listed twice",
+                number + 1
+            );
+        }
+
+        Ok(Self {
+            tokens: parsed,
+            key,
+            failures: Mutex::default(),
+        })
+    }
+
+    /// Whether anyone is let in, without a token.
+    pub fn is_open(&self) -> bool {
+        self.tokens.is_empty() && self.key.is_none()
+    }
+
+    /// Check the `Authorization` header of a request made at `now`, in
+    /// seconds since the Unix epoch.
+    pub fn authenticate(&self, header: Option<&str>, now: u64) -> Result<Caller, Rejection> {
+        if self.is_open() {
+            return Ok(Caller::anyone());
+        }
+
+        let header = header.ok_or(Rejection::Missing)?;
+        let token = header
+            .strip_prefix("Bearer ")
+            .map(str::trim)
+            .ok_or(Rejection::Malformed)?;
+
+        if let Some(caller) = self.tokens.get(blake3::hash(token.as_bytes()).as_bytes()) {
+            return Ok(caller.clone());
+        }
+
+        match &self.key {
+            Some(key) if token.split('.').count() == 3 => verify(key, token, now),
+            _ => Err(Rejection::Unknown),
+        }
+    }
+
+    /// Whether `peer` has failed too often to be let in for now.
+    pub fn is_limited(&self, peer: &Peer) -> bool {
+        if peer.0.is_none() {
+            return false;
+        }
+
+        let failures = self.failures.lock().unwrap();
+
+        failures.get(peer).is_some_and(|failures| {
+            failures.count >= MAX_FAILURES && failures.since.elapsed() < FAILURE_WINDOW
+        })
+    }
+
+    /// Count a failed attempt by `peer`, returning whether that was one too
+    /// many.
+    pub fn record_failure(&self, peer: &Peer) -> bool {
+        if peer.0.is_none() {
+            return false;
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+
+        if failures.len() >= MAX_PEERS {
+            failures.retain(|_, failures| failures.since.elapsed() < FAILURE_WINDOW);
+        }
+
+        let entry = failures.entry(*peer).or_insert(Failures {
+            count: 0,
+            since: Instant::now(),
+        });
+
+        if entry.since.elapsed() >= FAILURE_WINDOW {
+            *entry = Failures {
+                count: 0,
+                since: Instant::now(),
+            };
+        }
+
+        entry.count += 1;
+        entry.count == MAX_FAILURES
+    }
+}
+
+/// A static token's hash, and who it's for.
+fn parse_token_line(line: &str) -> anyhow::Result<([u8; 32], Caller)> {
+    let mut words = line.split_whitespace();
+    let (Some(name), Some(token)) = (words.next(), words.next()) else {
+        anyhow::bail!("expected `<name> <token>`");
+    };
+
+    let mut scope = Scope::default();
+
+    for word in words {
+        match word.split_once(':') {
+            Some(("root", root)) => scope.roots.push(root.into()),
+            Some(("pattern", pattern)) => scope.patterns.push(pattern.to_owned()),
+            None if word == "admin" => scope.admin = true,
+            _ => anyhow::bail!("`{word}` is neither `root:<path>`, `pattern:<glob>` nor `admin`"),
+        }
+    }
+
+    let caller = Caller::new(name.to_owned(), scope)?;
+
+    Ok((*blake3::hash(token.as_bytes()).as_bytes(), caller))
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::new_from_slice(key).expect("HMAC takes keys of any length")
+}
+
+/// A token for `name`, signed with `key`, that stops working after
+/// `valid_for`.
+pub fn mint(key: &[u8], name: &str, valid_for: Duration, scope: Scope) -> String {
+    let claims = Claims {
+        sub: name.to_owned(),
+        exp: unix_now() + valid_for.as_secs(),
+        scope,
+    };
+    let claims = serde_json::to_vec(&claims).expect("claims are always serializable");
+
+    let signed = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(JWT_HEADER),
+        URL_SAFE_NO_PAD.encode(claims)
+    );
+    let mut mac = hmac(key);
+    mac.update(signed.as_bytes());
+
+    format!(
+        "{signed}.{}",
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Check a signed token, and who it's for.
+fn verify(key: &[u8], token: &str, now: u64) -> Result<Caller, Rejection> {
+    let (signed, signature) = token.rsplit_once('.').ok_or(Rejection::Malformed)?;
+    let (header, claims) = signed.split_once('.').ok_or(Rejection::Malformed)?;
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| Rejection::Malformed)?;
+    let mut mac = hmac(key);
+    mac.update(signed.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| Rejection::BadSignature)?;
+
+    // Signed with our key, but anything other than HS256 wasn't meant for
+    // us.
+    let header: JwtHeader = decode_json(header)?;
+    if header.alg != "HS256" {
+        return Err(Rejection::Malformed);
+    }
+
+    let claims: Claims = decode_json(claims)?;
+    if claims.exp <= now {
+        return Err(Rejection::Expired);
+    }
+
+    Caller::new(claims.sub, claims.scope).map_err(|_| Rejection::Malformed)
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, Rejection> {
+    let json = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| Rejection::Malformed)?;
+
+    serde_json::from_slice(&json).map_err(|_| Rejection::Malformed)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Turn away requests without a valid token, and let the handlers know who
+/// made the rest.
+pub async fn authenticate(
+    State(auth): State<Arc<Auth>>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if auth.is_limited(&peer) {
+        return Rejection::TooManyFailures.into_response();
+    }
+
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok());
+
+    match auth.authenticate(header, unix_now()) {
+        Ok(caller) => {
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+        Err(rejection) => {
+            warn!(
+                "Rejected {} {} from {peer}: {rejection}",
+                request.method(),
+                request.uri().path()
+            );
+
+            if auth.record_failure(&peer) {
+                warn!(
+                    "Turning away {peer} for {}s after {MAX_FAILURES} failed attempts",
+                    FAILURE_WINDOW.as_secs()
+                );
+            }
+
+            rejection.into_response()
+        }
+    }
+}
+
+/// The other end of a connection, as far as telling callers apart goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Peer(Option<IpAddr>);
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        // IPv4 clients of a dual-stack listener arrive as mapped IPv6
+        // addresses, but they're the same clients as over IPv4.
+        Self(Some(addr.ip().to_canonical()))
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(ip) => write!(f, "{ip}"),
+            None => f.write_str("a local client"),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        (*stream.remote_addr()).into()
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        (*stream.remote_addr()).into()
+    }
+}
+
+/// Every client of a Unix socket is on this host, and they can't be told
+/// apart by address.
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for Peer {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Self(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_static_tokens() {
+        let tokens = "
+            # Just the logs.
+            logs s3cret root:/var/log pattern:**/*.log
+            admin t0ps3cret
+            ops 0ps root:/var/log admin
+        ";
+        let auth = Auth::new(Some(tokens), None).unwrap();
+        let check = |header| auth.authenticate(header, 0).map(|caller| caller.name);
+
+        assert_eq!(Ok("logs".to_owned()), check(Some("Bearer s3cret")));
+        assert_eq!(Ok("admin".to_owned()), check(Some("Bearer t0ps3cret")));
+        assert_eq!(Err(Rejection::Unknown), check(Some("Bearer s3cre")));
+        assert_eq!(Err(Rejection::Unknown), check(Some("Bearer a.b.c")));
+        assert_eq!(Err(Rejection::Malformed), check(Some("Basic s3cret")));
+        assert_eq!(Err(Rejection::Missing), check(None));
+
+        let logs = auth.authenticate(Some("Bearer s3cret"), 0).unwrap();
+        let include = logs.include().unwrap();
+        assert!(include.is_match(Path::new("/var/log/nginx/access.log")));
+        assert!(!include.is_match(Path::new("/var/log/wtmp")));
+        assert!(!logs.is_admin());
+
+        let admin = |token: &str| {
+            auth.authenticate(Some(&format!("Bearer {token}")), 0)
+                .unwrap()
+                .is_admin()
+        };
+        assert!(admin("t0ps3cret"));
+        assert!(admin("0ps"));
+        assert!(Caller::anyone().is_admin());
+
+        assert!(Auth::new(Some("lonely"), None).is_err());
+        assert!(Auth::new(Some("a b c"), None).is_err());
+        assert!(Auth::new(Some("a b root:/var/../etc"), None).is_err());
+        assert!(Auth::new(Some("a b pattern:/usr/["), None).is_err());
+        assert!(Auth::new(Some("a b admin:yes"), None).is_err());
+        assert!(Auth::new(Some("a b\nc b"), None).is_err());
+
+        assert!(Auth::default().is_open());
+        assert_eq!(
+            "anyone",
+            Auth::default().authenticate(None, 0).unwrap().name
+        );
+    }
+
+    #[test]
+    fn test_signed_tokens() {
+        let scope = Scope {
+            roots: vec!["/home/alice".into()],
+            ..Default::default()
+        };
+        let token = mint(KEY, "alice", Duration::from_secs(60), scope);
+        let header = format!("Bearer {token}");
+        let auth = Auth::new(None, Some(KEY.to_vec())).unwrap();
+
+        let alice = auth.authenticate(Some(&header), unix_now()).unwrap();
+        assert_eq!("alice", alice.name);
+        assert_eq!(
+            vec![PathBuf::from("/home/alice")],
+            alice.roots_within(&["/home".into(), "/etc".into()])
+        );
+        assert!(alice.include().is_none());
+
+        assert_eq!(
+            Err(Rejection::Expired),
+            auth.authenticate(Some(&header), unix_now() + 60)
+                .map(|c| c.name)
+        );
+
+        let other = Auth::new(None, Some([KEY, b"!"].concat())).unwrap();
+        assert_eq!(
+            Err(Rejection::BadSignature),
+            other
+                .authenticate(Some(&header), unix_now())
+                .map(|c| c.name)
+        );
+
+        // Claiming someone else, or a wider scope, breaks the signature.
+        let (header_part, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = Claims {
+            sub: "alice".to_owned(),
+            exp: u64::MAX,
+            scope: Scope::default(),
+        };
+        let forged = format!(
+            "Bearer {header_part}.{}.{signature}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap())
+        );
+        assert_eq!(
+            Err(Rejection::BadSignature),
+            auth.authenticate(Some(&forged), unix_now()).map(|c| c.name)
+        );
+
+        assert!(Auth::new(None, Some(b"short".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_roots_within() {
+        let caller = Caller::new(
+            "someone".to_owned(),
+            Scope {
+                roots: vec!["/var/log".into(), "/srv".into()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let within = |roots: &[&str]| {
+            let roots: Vec<PathBuf> = roots.iter().map(PathBuf::from).collect();
+            caller.roots_within(&roots)
+        };
+
+        assert_eq!(
+            vec![PathBuf::from("/var/log"), PathBuf::from("/srv")],
+            within(&["/"])
+        );
+        assert_eq!(
+            vec![PathBuf::from("/srv/www")],
+            within(&["/srv/www", "/etc"])
+        );
+        // Not a path prefix, just a string one.
+        assert!(within(&["/var/logs", "/srv2"]).is_empty());
+        assert_eq!(
+            vec![PathBuf::from("/etc")],
+            Caller::anyone().roots_within(&["/etc".into()])
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let auth = Auth::new(Some("a b"), None).unwrap();
+        let peer = Peer::from(SocketAddr::from(([192, 0, 2, 1], 4000)));
+        let other = Peer::from(SocketAddr::from(([192, 0, 2, 2], 4000)));
+
+        for _ in 1..MAX_FAILURES {
+            assert!(!auth.record_failure(&peer));
+            assert!(!auth.is_limited(&peer));
+        }
+
+        assert!(auth.record_failure(&peer));
+        assert!(auth.is_limited(&peer));
+        assert!(!auth.is_limited(&other));
+
+        // Local clients all look the same, so none of them is held to it.
+        let local = Peer(None);
+        for _ in 0..MAX_FAILURES * 2 {
+            assert!(!auth.record_failure(&local));
+        }
+        assert!(!auth.is_limited(&local));
+    }
+}
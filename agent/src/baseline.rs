@@ -0,0 +1,270 @@
+//! What a previous collection found, so that the next one only sends what
+//! changed since.
+
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, ensure};
+use serde::Deserialize;
+
+use crate::{
+    digest::{Digest, DigestAlgorithm},
+    known::from_hex,
+    stream::{EntryMetadata, path_from_bytes},
+};
+
+/// The files of a previous collection, from the manifest the decoder wrote
+/// for it.
+#[derive(Debug, Default)]
+pub struct Baseline {
+    files: Vec<BaselineFile>,
+    /// By the path's raw bytes, so that names which aren't valid UTF-8
+    /// can't be mistaken for each other.
+    by_path: HashMap<Vec<u8>, usize>,
+}
+
+/// A file as the baseline saw it.
+#[derive(Debug, Clone)]
+pub struct BaselineFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: i64,
+    /// Missing from older manifests, in which case only seconds are compared.
+    pub mtime_nsec: Option<u32>,
+    /// Missing from older manifests, and zero where there are no inodes.
+    pub inode: Option<u64>,
+    pub digests: Vec<Digest>,
+}
+
+/// One line of a manifest, keeping only what's needed here.
+#[derive(Debug, Deserialize)]
+struct ManifestRow {
+    path: String,
+    /// The raw bytes of a path that isn't valid UTF-8, in hex.
+    #[serde(default)]
+    path_hex: String,
+    kind: String,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: Option<u32>,
+    inode: Option<u64>,
+    #[serde(default)]
+    partial: bool,
+    #[serde(default)]
+    md5: String,
+    #[serde(default)]
+    sha1: String,
+    #[serde(default)]
+    sha256: String,
+    #[serde(default)]
+    sha3_256: String,
+    #[serde(default)]
+    blake3: String,
+}
+
+impl Baseline {
+    /// Parse a manifest written by the decoder, as JSON or CSV. Files that
+    /// were cut off in the previous collection are left out, so that
+    /// they're sent again.
+    pub fn parse(manifest: &[u8]) -> anyhow::Result<Self> {
+        let rows: Vec<ManifestRow> = match manifest.trim_ascii_start().first() {
+            Some(b'[') => serde_json::from_slice(manifest).context("invalid JSON manifest")?,
+            _ => {
+                let mut reader = csv::Reader::from_reader(manifest);
+                let headers = reader.headers().context("invalid CSV manifest")?;
+                ensure!(
+                    headers.iter().any(|header| header == "path"),
+                    "not a manifest, there's no `path` column"
+                );
+
+                reader
+                    .deserialize()
+                    .collect::<Result<_, _>>()
+                    .context("invalid CSV manifest")?
+            }
+        };
+
+        let mut baseline = Self::default();
+
+        for row in rows {
+            if row.kind != "file" || row.partial {
+                continue;
+            }
+
+            let digests = [
+                (DigestAlgorithm::Md5, &row.md5),
+                (DigestAlgorithm::Sha1, &row.sha1),
+                (DigestAlgorithm::Sha256, &row.sha256),
+                (DigestAlgorithm::Sha3_256, &row.sha3_256),
+                (DigestAlgorithm::Blake3, &row.blake3),
+            ]
+            .into_iter()
+            .filter(|(_, hex)| !hex.is_empty())
+            .map(|(algorithm, hex)| {
+                let value = from_hex(hex)
+                    .filter(|value| value.len() == algorithm.digest_len())
+                    .with_context(|| format!("{}: invalid {algorithm} digest", row.path))?;
+
+                Ok(Digest { algorithm, value })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+            let path = match row.path_hex.is_empty() {
+                true => row.path.into_bytes(),
+                false => from_hex(&row.path_hex)
+                    .with_context(|| format!("{}: invalid path_hex", row.path))?,
+            };
+
+            baseline.by_path.insert(path.clone(), baseline.files.len());
+            baseline.files.push(BaselineFile {
+                path: path_from_bytes(&path),
+                size: row.size,
+                mtime: row.mtime,
+                mtime_nsec: row.mtime_nsec,
+                inode: row.inode,
+                digests,
+            });
+        }
+
+        Ok(baseline)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The baseline's entry for `path`, along with its index.
+    pub fn get(&self, path: &Path) -> Option<(usize, &BaselineFile)> {
+        let index = *self.by_path.get(path.as_os_str().as_encoded_bytes())?;
+
+        Some((index, &self.files[index]))
+    }
+
+    pub fn files(&self) -> &[BaselineFile] {
+        &self.files
+    }
+}
+
+impl BaselineFile {
+    /// Whether the file looks untouched since the baseline, going by its
+    /// size, modification time and inode.
+    pub fn is_unchanged(&self, meta: &Metadata) -> bool {
+        let metadata = EntryMetadata::from(meta);
+
+        self.size == meta.len()
+            && self.mtime == metadata.mtime
+            && self
+                .mtime_nsec
+                .is_none_or(|nsec| nsec == metadata.mtime_nsec)
+            && self
+                .inode
+                .filter(|&inode| inode != 0)
+                .is_none_or(|inode| inode == metadata.inode)
+    }
+
+    /// A digest to check a file against when its metadata changed but its
+    /// size didn't, preferring one of `algorithms`.
+    pub fn digest(&self, algorithms: &[DigestAlgorithm]) -> Option<&Digest> {
+        self.digests
+            .iter()
+            .find(|digest| algorithms.contains(&digest.algorithm))
+            .or(self.digests.first())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passwd");
+        fs::write(&path, "root:x:0:0").unwrap();
+
+        let meta = fs::metadata(&path).unwrap();
+        let metadata = EntryMetadata::from(&meta);
+        let csv = format!(
+            "path,kind,size,mode,uid,gid,mtime,mtime_nsec,inode,partial,collection,atime_preserved,md5,sha1,sha256,sha3_256,blake3\n\
+             {path},file,10,0644,0,0,{mtime},{nsec},{inode},false,full,true,{md5},,,,\n\
+             {dir},directory,0,0755,0,0,0,0,0,false,,false,,,,,\n\
+             /cut/off,file,10,0644,0,0,0,0,0,true,full,true,,,,,\n",
+            path = path.display(),
+            dir = dir.path().display(),
+            mtime = metadata.mtime,
+            nsec = metadata.mtime_nsec,
+            inode = metadata.inode,
+            md5 = "ab".repeat(16),
+        );
+
+        let baseline = Baseline::parse(csv.as_bytes()).unwrap();
+        assert_eq!(1, baseline.len());
+
+        let (index, file) = baseline.get(&path).unwrap();
+        assert_eq!(0, index);
+        assert!(file.is_unchanged(&meta));
+        assert_eq!(
+            Some(DigestAlgorithm::Md5),
+            file.digest(&[DigestAlgorithm::Sha256]).map(|d| d.algorithm)
+        );
+
+        fs::write(&path, "root:x:0:1").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+        assert!(!file.is_unchanged(&fs::metadata(&path).unwrap()));
+
+        // The same, written as JSON, and without the newer columns.
+        let json = format!(
+            r#"[{{"path":"{}","kind":"file","size":10,"mtime":{},"partial":false}}]"#,
+            path.display(),
+            metadata.mtime
+        );
+        let baseline = Baseline::parse(json.as_bytes()).unwrap();
+        assert!(baseline.get(&path).unwrap().1.is_unchanged(&meta));
+
+        assert!(Baseline::parse(b"path,kind\n/a,file\n").is_err());
+        assert!(Baseline::parse(b"junk").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        // Both show up as `/tmp/\u{fffd}`, but only by their hex.
+        let json = r#"[
+            {"path":"/tmp/\ufffd","path_hex":"2f746d702fff","kind":"file","size":1,"mtime":0,"partial":false},
+            {"path":"/tmp/\ufffd","path_hex":"2f746d702ffe","kind":"file","size":2,"mtime":0,"partial":false}
+        ]"#;
+        let baseline = Baseline::parse(json.as_bytes()).unwrap();
+
+        let size = |name: &[u8]| {
+            baseline
+                .get(Path::new(OsStr::from_bytes(name)))
+                .map(|(_, file)| file.size)
+        };
+        assert_eq!(Some(1), size(b"/tmp/\xff"));
+        assert_eq!(Some(2), size(b"/tmp/\xfe"));
+        assert_eq!(None, size("/tmp/\u{fffd}".as_bytes()));
+        assert_eq!(
+            Path::new(OsStr::from_bytes(b"/tmp/\xff")),
+            baseline.files()[0].path
+        );
+    }
+}
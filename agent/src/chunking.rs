@@ -0,0 +1,105 @@
+//! Content-defined chunking, so that data repeated across files, such as
+//! copies of the same library or log files that share a prefix, is sent once
+//! per stream.
+//!
+//! Cut points depend only on the bytes around them, so an insertion early in
+//! a file doesn't shift every chunk after it.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io::{self, Read},
+    sync::Mutex,
+};
+
+use bytes::{Buf, Bytes};
+
+/// Chunk sizes, in bytes. The average is about what a file is read in
+/// anyway, and the maximum keeps a chunk well within a frame.
+pub const MIN_CHUNK: u32 = 16 * 1024;
+pub const AVG_CHUNK: u32 = 64 * 1024;
+pub const MAX_CHUNK: u32 = 256 * 1024;
+
+/// The BLAKE3 hash of a chunk's uncompressed contents.
+pub type ChunkHash = [u8; 32];
+
+/// The chunks already sent in a stream, by the position in walk order of the
+/// file that sends each one.
+///
+/// Files are encoded in parallel but sent in walk order, so a chunk may only
+/// be referenced by a file that comes after the one sending it. When an
+/// earlier file turns out to need a chunk a later one claimed, it sends the
+/// chunk again and becomes its sender instead.
+///
+/// Each chunk costs a few dozen bytes, so a stream of a terabyte of unique
+/// data holds on to about a gigabyte here.
+#[derive(Debug, Default)]
+pub struct SentChunks {
+    senders: Mutex<HashMap<ChunkHash, u64>>,
+}
+
+impl SentChunks {
+    /// Whether file `file`, counting in walk order, can refer to `hash`
+    /// rather than send it. If not, the file must send it.
+    pub fn claim(&self, hash: ChunkHash, file: u64) -> bool {
+        match self.senders.lock().unwrap().entry(hash) {
+            Entry::Occupied(entry) if *entry.get() <= file => true,
+            Entry::Occupied(mut entry) => {
+                entry.insert(file);
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(file);
+                false
+            }
+        }
+    }
+}
+
+/// Adapts the blocks a file is read in to the `Read` the chunker wants.
+pub struct Blocks<F> {
+    pending: Bytes,
+    next: F,
+}
+
+impl<F: FnMut() -> io::Result<Bytes>> Blocks<F> {
+    /// Read `first`, and then whatever `next` returns until it's empty.
+    pub fn new(first: Bytes, next: F) -> Self {
+        Self {
+            pending: first,
+            next,
+        }
+    }
+}
+
+impl<F: FnMut() -> io::Result<Bytes>> Read for Blocks<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            self.pending = (self.next)()?;
+        }
+
+        let len = self.pending.len().min(buf.len());
+        self.pending.copy_to_slice(&mut buf[..len]);
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sent_chunks() {
+        let sent = SentChunks::default();
+        let (a, b) = ([1; 32], [2; 32]);
+
+        assert!(!sent.claim(a, 5));
+        // Again in the same file, or in any later one.
+        assert!(sent.claim(a, 5));
+        assert!(sent.claim(a, 9));
+        // An earlier file can't rely on a later one.
+        assert!(!sent.claim(a, 2));
+        assert!(sent.claim(a, 3));
+        assert!(!sent.claim(b, 3));
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    mem,
+    str::FromStr,
+};
+
+use bytes::Bytes;
+use lz4_flex::frame::{BlockMode, FrameEncoder, FrameInfo};
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// How one file's contents are compressed on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug, Clone, Copy, PartialEq, Eq))]
+pub enum Codec {
+    /// Sent as-is.
+    Store,
+    /// A single LZ4 frame with linked blocks.
+    Lz4,
+    /// A single zstd frame.
+    Zstd,
+}
+
+impl From<ArchivedCodec> for Codec {
+    fn from(archived: ArchivedCodec) -> Self {
+        match archived {
+            ArchivedCodec::Store => Self::Store,
+            ArchivedCodec::Lz4 => Self::Lz4,
+            ArchivedCodec::Zstd => Self::Zstd,
+        }
+    }
+}
+
+/// What a collection compresses files with, written as `store`, `lz4`,
+/// `zstd` or `zstd:<level>`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Store,
+    #[default]
+    Lz4,
+    Zstd {
+        level: i32,
+    },
+}
+
+/// Signatures of formats whose contents are already compressed, by offset.
+const COMPRESSED_SIGNATURES: &[(usize, &[u8])] = &[
+    (0, b"\xff\xd8\xff"),       // JPEG
+    (0, b"\x89PNG\r\n\x1a\n"),  // PNG
+    (0, b"GIF8"),               // GIF
+    (0, b"PK\x03\x04"),         // zip, and everything built on it
+    (0, b"\x1f\x8b"),           // gzip
+    (0, b"BZh"),                // bzip2
+    (0, b"\xfd7zXZ\x00"),       // xz
+    (0, b"7z\xbc\xaf\x27\x1c"), // 7-Zip
+    (0, b"Rar!\x1a\x07"),       // RAR
+    (0, b"\x28\xb5\x2f\xfd"),   // zstd
+    (0, b"\x04\x22\x4d\x18"),   // LZ4
+    (0, b"OggS"),               // Ogg
+    (0, b"fLaC"),               // FLAC
+    (0, b"ID3"),                // MP3
+    (4, b"ftyp"),               // MP4, MOV, HEIC
+    (8, b"WEBP"),               // WebP
+];
+
+/// Trial blocks smaller than this say too little to go on.
+const MIN_TRIAL_LEN: usize = 4096;
+
+/// A trial block that doesn't shrink below this fraction of its size isn't
+/// worth compressing.
+const MAX_USEFUL_RATIO: f64 = 0.95;
+
+impl Compression {
+    /// Pick the codec for a file from the first block of its contents. Data
+    /// that's already compressed is stored rather than compressed again.
+    pub fn codec_for(self, first_block: &[u8]) -> Codec {
+        let codec = match self {
+            Self::Store => return Codec::Store,
+            Self::Lz4 => Codec::Lz4,
+            Self::Zstd { .. } => Codec::Zstd,
+        };
+
+        if is_compressed(first_block) {
+            Codec::Store
+        } else {
+            codec
+        }
+    }
+
+    fn zstd_level(self) -> i32 {
+        match self {
+            Self::Zstd { level } => level,
+            _ => zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+fn is_compressed(block: &[u8]) -> bool {
+    let signed = COMPRESSED_SIGNATURES
+        .iter()
+        .any(|(offset, magic)| block.get(*offset..).is_some_and(|b| b.starts_with(magic)));
+
+    if signed || block.len() < MIN_TRIAL_LEN {
+        return signed;
+    }
+
+    let compressed = lz4_flex::block::compress(block);
+    compressed.len() as f64 >= block.len() as f64 * MAX_USEFUL_RATIO
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Store => f.write_str("store"),
+            Self::Lz4 => f.write_str("lz4"),
+            Self::Zstd { level } => write!(f, "zstd:{level}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownCompression(String);
+
+impl fmt::Display for UnknownCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown compression `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCompression {}
+
+impl FromStr for Compression {
+    type Err = UnknownCompression;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || UnknownCompression(s.to_owned());
+
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("store") => Ok(Self::Store),
+            None if s.eq_ignore_ascii_case("lz4") => Ok(Self::Lz4),
+            None if s.eq_ignore_ascii_case("zstd") => Ok(Self::Zstd {
+                level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            }),
+            Some((name, level)) if name.eq_ignore_ascii_case("zstd") => {
+                let level = level.parse().map_err(|_| unknown())?;
+
+                if zstd::compression_level_range().contains(&level) {
+                    Ok(Self::Zstd { level })
+                } else {
+                    Err(unknown())
+                }
+            }
+            _ => Err(unknown()),
+        }
+    }
+}
+
+/// Compresses one file's contents, handing over the output as it becomes
+/// available.
+pub enum Encoder {
+    Store,
+    Lz4(FrameEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(codec: Codec, compression: Compression) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::Store => Self::Store,
+            Codec::Lz4 => {
+                let frame_info = FrameInfo::new().block_mode(BlockMode::Linked);
+                Self::Lz4(FrameEncoder::with_frame_info(frame_info, Vec::new()))
+            }
+            Codec::Zstd => Self::Zstd(zstd::Encoder::new(Vec::new(), compression.zstd_level())?),
+        })
+    }
+
+    /// Compress the next chunk of the file, returning whatever compressed
+    /// output is ready so far. Stored chunks are passed straight through.
+    pub fn encode(&mut self, chunk: Bytes) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Store => return Ok(chunk),
+            Self::Lz4(encoder) => {
+                encoder.write_all(&chunk)?;
+                encoder.get_mut()
+            }
+            Self::Zstd(encoder) => {
+                encoder.write_all(&chunk)?;
+                encoder.get_mut()
+            }
+        };
+
+        Ok(mem::take(output).into())
+    }
+
+    /// Flush out the rest of the compressed output.
+    pub fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Store => Vec::new(),
+            Self::Lz4(encoder) => encoder.finish().map_err(io::Error::other)?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+
+        Ok(output.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_for() {
+        let text = "all work and no play makes jack a dull boy\n".repeat(200);
+        let mut state = 0x2545f491u32;
+        let noise: Vec<u8> = (0..64 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let jpeg = [b"\xff\xd8\xff\xe0".as_slice(), text.as_bytes()].concat();
+
+        let zstd = Compression::Zstd { level: 3 };
+        assert_eq!(Codec::Zstd, zstd.codec_for(text.as_bytes()));
+        assert_eq!(Codec::Store, zstd.codec_for(&noise));
+        assert_eq!(Codec::Store, zstd.codec_for(&jpeg));
+        assert_eq!(Codec::Lz4, Compression::Lz4.codec_for(b""));
+        assert_eq!(Codec::Store, Compression::Store.codec_for(text.as_bytes()));
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!(Ok(Compression::Lz4), "lz4".parse().map_err(drop));
+        assert_eq!(
+            Ok(Compression::Zstd { level: 19 }),
+            "zstd:19".parse().map_err(drop)
+        );
+        assert!("zstd:fast".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+}
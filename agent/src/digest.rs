@@ -0,0 +1,192 @@
+use std::{fmt, str::FromStr};
+
+use md5::Md5;
+use rkyv::{Archive, Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::Sha256;
+use sha3::{Digest as _, Sha3_256};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Archive, Serialize, Deserialize,
+)]
+#[rkyv(derive(Debug, Clone, Copy, PartialEq, Eq))]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha3_256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    pub const ALL: [Self; 5] = [
+        Self::Sha256,
+        Self::Sha3_256,
+        Self::Sha1,
+        Self::Md5,
+        Self::Blake3,
+    ];
+
+    /// What's used when a collection doesn't ask for anything else.
+    pub const DEFAULT: [Self; 2] = [Self::Sha256, Self::Md5];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha3_256 => "sha3-256",
+            Self::Sha1 => "sha1",
+            Self::Md5 => "md5",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// How many bytes a digest is.
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 | Self::Sha3_256 | Self::Blake3 => 32,
+        }
+    }
+
+    /// Parse a comma separated list such as `sha256,blake3`, dropping
+    /// duplicates.
+    pub fn parse_list(list: &str) -> Result<Vec<Self>, UnknownAlgorithm> {
+        let mut algorithms = list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Self>, _>>()?;
+
+        algorithms.sort();
+        algorithms.dedup();
+
+        Ok(algorithms)
+    }
+}
+
+impl From<ArchivedDigestAlgorithm> for DigestAlgorithm {
+    fn from(archived: ArchivedDigestAlgorithm) -> Self {
+        match archived {
+            ArchivedDigestAlgorithm::Sha256 => Self::Sha256,
+            ArchivedDigestAlgorithm::Sha3_256 => Self::Sha3_256,
+            ArchivedDigestAlgorithm::Sha1 => Self::Sha1,
+            ArchivedDigestAlgorithm::Md5 => Self::Md5,
+            ArchivedDigestAlgorithm::Blake3 => Self::Blake3,
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownAlgorithm(String);
+
+impl fmt::Display for UnknownAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown digest algorithm `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownAlgorithm {}
+
+impl FromStr for DigestAlgorithm {
+    type Err = UnknownAlgorithm;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| UnknownAlgorithm(name.to_owned()))
+    }
+}
+
+/// A finished digest of a file's contents.
+#[derive(Debug, Clone, PartialEq, Eq, Archive, Serialize, Deserialize)]
+pub struct Digest {
+    pub algorithm: DigestAlgorithm,
+    pub value: Vec<u8>,
+}
+
+impl Digest {
+    /// The value as lowercase hex.
+    pub fn hex(&self) -> String {
+        self.value
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl From<&ArchivedDigest> for Digest {
+    fn from(archived: &ArchivedDigest) -> Self {
+        Self {
+            algorithm: archived.algorithm.into(),
+            value: archived.value.to_vec(),
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha3_256(Box<Sha3_256>),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Calculates several digests over the same data in one pass.
+pub struct Hashers(Vec<Hasher>);
+
+impl Hashers {
+    pub fn new(algorithms: &[DigestAlgorithm]) -> Self {
+        let hashers = algorithms
+            .iter()
+            .map(|algorithm| match algorithm {
+                DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+                DigestAlgorithm::Sha3_256 => Hasher::Sha3_256(Box::default()),
+                DigestAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+                DigestAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+                DigestAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            })
+            .collect();
+
+        Self(hashers)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for hasher in &mut self.0 {
+            match hasher {
+                Hasher::Sha256(hasher) => hasher.update(data),
+                Hasher::Sha3_256(hasher) => hasher.update(data),
+                Hasher::Sha1(hasher) => hasher.update(data),
+                Hasher::Md5(hasher) => hasher.update(data),
+                Hasher::Blake3(hasher) => {
+                    hasher.update(data);
+                }
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Vec<Digest> {
+        self.0
+            .into_iter()
+            .map(|hasher| match hasher {
+                Hasher::Sha256(hasher) => (DigestAlgorithm::Sha256, hasher.finalize().to_vec()),
+                Hasher::Sha3_256(hasher) => (DigestAlgorithm::Sha3_256, hasher.finalize().to_vec()),
+                Hasher::Sha1(hasher) => (DigestAlgorithm::Sha1, hasher.finalize().to_vec()),
+                Hasher::Md5(hasher) => (DigestAlgorithm::Md5, hasher.finalize().to_vec()),
+                Hasher::Blake3(hasher) => (
+                    DigestAlgorithm::Blake3,
+                    hasher.finalize().as_bytes().to_vec(),
+                ),
+            })
+            .map(|(algorithm, value)| Digest { algorithm, value })
+            .collect()
+    }
+}
@@ -0,0 +1,337 @@
+//! Encryption of a whole stream to one or more recipients, so that whoever
+//! can see it in transit or at rest can't read what was collected.
+//!
+//! An encrypted stream starts with a header:
+//!
+//! - `MAGIC`, then the format version as a byte
+//! - an ephemeral X25519 public key
+//! - the number of recipients as a byte, then for each, the stream's key
+//!   sealed with ChaCha20-Poly1305 under a key agreed between the ephemeral
+//!   key and the recipient's
+//!
+//! The framed stream follows, cut into `SEGMENT_LEN` segments each sealed
+//! with ChaCha20-Poly1305 in the STREAM construction, so that segments can't
+//! be reordered, dropped or cut off without the decoder noticing. The
+//! segments' key is derived from the stream's key and the header, binding
+//! the two together.
+
+use std::{fmt, io, pin::pin, str::FromStr};
+
+use async_fn_stream::try_fn_stream;
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{
+        Aead, OsRng,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use futures::{TryStream, TryStreamExt};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+use crate::known::from_hex;
+
+/// Starts every encrypted stream, in place of a frame's magic.
+pub const MAGIC: [u8; 4] = *b"AGFE";
+
+pub const VERSION: u8 = 1;
+
+/// How much of the framed stream each segment holds, before its tag.
+pub const SEGMENT_LEN: usize = 64 * 1024;
+
+/// The Poly1305 tag ending every sealed segment and key.
+pub const TAG_LEN: usize = 16;
+
+/// A stream's key, sealed for one recipient.
+const SEALED_KEY_LEN: usize = 32 + TAG_LEN;
+
+/// The header, up to the sealed keys.
+const HEADER_LEN: usize = MAGIC.len() + 1 + 32 + 1;
+
+const RECIPIENT_INFO: &[u8] = b"agentfs recipient";
+const PAYLOAD_INFO: &[u8] = b"agentfs payload";
+
+/// Decrypts the segments following a header, in order.
+pub type SegmentDecryptor = DecryptorBE32<ChaCha20Poly1305>;
+
+/// Someone a stream can be encrypted to, by their X25519 public key.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// The private half of a `Recipient`, for decrypting what's sent to them.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random())
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+}
+
+/// A key that isn't 64 hex digits.
+#[derive(Debug)]
+pub struct InvalidKey;
+
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a key must be 32 bytes of hex")
+    }
+}
+
+impl std::error::Error for InvalidKey {}
+
+fn parse_key(s: &str) -> Result<[u8; 32], InvalidKey> {
+    from_hex(s.trim())
+        .and_then(|key| key.try_into().ok())
+        .ok_or(InvalidKey)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+impl FromStr for Recipient {
+    type Err = InvalidKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_key(s).map(|key| Self(PublicKey::from(key)))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = InvalidKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_key(s).map(|key| Self(StaticSecret::from(key)))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recipient({self})")
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self.recipient())
+    }
+}
+
+/// Parse comma separated recipients, as the FFI takes them.
+pub fn parse_recipients(list: &str) -> Result<Vec<Recipient>, InvalidKey> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Encrypt a framed stream to every one of `recipients`, any of whom can
+/// decrypt it.
+pub fn encrypt<S>(
+    stream: S,
+    recipients: Vec<Recipient>,
+) -> impl TryStream<Ok = Bytes, Error = io::Error>
+where
+    S: TryStream<Ok = Bytes, Error = io::Error>,
+{
+    try_fn_stream(|emitter| async move {
+        let (header, cipher) = seal_header(&recipients)?;
+        let mut encryptor = EncryptorBE32::from_aead(cipher, &Default::default());
+        emitter.emit(header.into()).await;
+
+        let mut stream = pin!(stream.into_stream());
+        let mut pending = BytesMut::new();
+
+        while let Some(bytes) = stream.try_next().await? {
+            pending.extend_from_slice(&bytes);
+
+            // The last segment is sealed differently, so one is only sealed
+            // once something is known to follow it.
+            while pending.len() > SEGMENT_LEN {
+                let segment = pending.split_to(SEGMENT_LEN);
+                let sealed = encryptor
+                    .encrypt_next(segment.as_ref())
+                    .map_err(|_| io::Error::other("failed to encrypt a segment"))?;
+
+                emitter.emit(sealed.into()).await;
+            }
+        }
+
+        let sealed = encryptor
+            .encrypt_last(pending.as_ref())
+            .map_err(|_| io::Error::other("failed to encrypt a segment"))?;
+        emitter.emit(sealed.into()).await;
+
+        Ok(())
+    })
+}
+
+/// A new header sealing a random key for `recipients`, along with the
+/// cipher for the segments after it.
+fn seal_header(recipients: &[Recipient]) -> io::Result<(Vec<u8>, ChaCha20Poly1305)> {
+    let count = u8::try_from(recipients.len())
+        .ok()
+        .filter(|&count| count > 0)
+        .ok_or_else(|| io::Error::other("a stream needs between 1 and 255 recipients"))?;
+
+    let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+    // Fresh for every stream, but agreed with each recipient in turn.
+    let ephemeral = StaticSecret::random();
+    let ephemeral_public = PublicKey::from(&ephemeral);
+
+    let mut header = Vec::with_capacity(HEADER_LEN + recipients.len() * SEALED_KEY_LEN);
+    header.extend_from_slice(&MAGIC);
+    header.push(VERSION);
+    header.extend_from_slice(ephemeral_public.as_bytes());
+    header.push(count);
+
+    for Recipient(public) in recipients {
+        let shared = ephemeral.diffie_hellman(public);
+        let wrapping = wrapping_key(&shared, &ephemeral_public, public).ok_or_else(|| {
+            io::Error::other(format!("`{}` isn't a usable key", Recipient(*public)))
+        })?;
+        let sealed = wrapping
+            .encrypt(&Nonce::default(), &key[..])
+            .map_err(|_| io::Error::other("failed to seal the stream's key"))?;
+
+        header.extend_from_slice(&sealed);
+    }
+
+    let cipher = segment_cipher(&key, &header);
+
+    Ok((header, cipher))
+}
+
+/// Read a header from the start of `reader`, and open the key sealed in it
+/// for `identity`. `Ok(None)` if the stream isn't encrypted to them.
+pub fn open_header(
+    reader: &mut impl io::Read,
+    identity: &Identity,
+) -> io::Result<Option<SegmentDecryptor>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+
+    let mut header = vec![0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+
+    if header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not an encrypted stream"));
+    }
+
+    if header[MAGIC.len()] != VERSION {
+        return Err(invalid("unsupported encrypted stream version"));
+    }
+
+    let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(&header[5..37]).unwrap());
+    let count = header[HEADER_LEN - 1] as usize;
+
+    header.resize(HEADER_LEN + count * SEALED_KEY_LEN, 0);
+    reader.read_exact(&mut header[HEADER_LEN..])?;
+
+    let shared = identity.0.diffie_hellman(&ephemeral_public);
+    let public = PublicKey::from(&identity.0);
+    let Some(wrapping) = wrapping_key(&shared, &ephemeral_public, &public) else {
+        return Ok(None);
+    };
+
+    let key = header[HEADER_LEN..]
+        .chunks_exact(SEALED_KEY_LEN)
+        .find_map(|sealed| wrapping.decrypt(&Nonce::default(), sealed).ok())
+        .and_then(|key| <[u8; 32]>::try_from(key).ok());
+
+    Ok(key.map(|key| {
+        let cipher = segment_cipher(&Key::from(key), &header);
+        DecryptorBE32::from_aead(cipher, &Default::default())
+    }))
+}
+
+/// The key sealing a stream's key for the recipient with `public`, from
+/// what they and the stream's ephemeral key agreed on. `None` for keys that
+/// would agree on a predictable value.
+fn wrapping_key(
+    shared: &SharedSecret,
+    ephemeral_public: &PublicKey,
+    public: &PublicKey,
+) -> Option<ChaCha20Poly1305> {
+    if !shared.was_contributory() {
+        return None;
+    }
+
+    let salt = [ephemeral_public.as_bytes().as_slice(), public.as_bytes()].concat();
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(RECIPIENT_INFO, &mut key)
+        .unwrap();
+
+    Some(ChaCha20Poly1305::new(&key))
+}
+
+/// The cipher for the segments, from the stream's key and its header.
+fn segment_cipher(key: &Key, header: &[u8]) -> ChaCha20Poly1305 {
+    let mut segment_key = Key::default();
+    Hkdf::<Sha256>::new(Some(header), key)
+        .expand(PAYLOAD_INFO, &mut segment_key)
+        .unwrap();
+
+    ChaCha20Poly1305::new(&segment_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let identity = Identity::generate();
+        let recipient = identity.recipient();
+
+        let parsed: Identity = format!(" {identity}\n").parse().unwrap();
+        assert_eq!(recipient, parsed.recipient());
+        assert_eq!(recipient, recipient.to_string().parse().unwrap());
+
+        let other = Identity::generate().recipient();
+        assert_eq!(
+            vec![recipient, other],
+            parse_recipients(&format!("{recipient}, {other},")).unwrap()
+        );
+        assert!(parse_recipients("").unwrap().is_empty());
+        assert!(parse_recipients("abcd").is_err());
+        assert!("zz".repeat(32).parse::<Recipient>().is_err());
+
+        // The all-zero key agrees on zero with everyone.
+        assert!(seal_header(&["00".repeat(32).parse().unwrap()]).is_err());
+        assert!(seal_header(&[]).is_err());
+
+        let (header, _) = seal_header(&[other, recipient]).unwrap();
+        assert!(
+            open_header(&mut header.as_slice(), &identity)
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            open_header(&mut header.as_slice(), &Identity::generate())
+                .unwrap()
+                .is_none()
+        );
+        assert!(open_header(&mut &b"AGFS\x01"[..], &identity).is_err());
+    }
+}
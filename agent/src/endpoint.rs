@@ -0,0 +1,240 @@
+//! Where the web service listens: a port on every interface, one address,
+//! or a Unix domain socket for collectors on the same host.
+
+use std::{fmt, io, net::SocketAddr, str::FromStr};
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt, path::PathBuf};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// The port the FFI listens on when not told otherwise.
+pub const DEFAULT_PORT: u16 = 9001;
+
+/// How many connections can wait to be accepted.
+const BACKLOG: i32 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Every interface, over IPv6 and IPv4 alike, or only IPv4 on hosts
+    /// without IPv6. Written as just the port.
+    Port(u16),
+    /// One address, e.g. `127.0.0.1:9001` or `[::1]:9001`. An IPv6 address
+    /// only takes IPv6 clients, even when it's `[::]`, so that it can be
+    /// listed alongside `0.0.0.0` on the same port.
+    Address(SocketAddr),
+    /// A Unix domain socket, written `unix:<path>`. Whoever can reach the
+    /// path can connect, so it belongs in a directory only they can enter.
+    /// It always serves plain HTTP, as only local clients can reach it.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A listener bound to an `Endpoint`.
+pub enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Endpoint {
+    /// Start listening. Must be called from within a Tokio runtime.
+    ///
+    /// A Unix socket left behind by an agent that's no longer running is
+    /// replaced, but not one that's still taking connections.
+    pub fn bind(&self) -> io::Result<Bound> {
+        match self {
+            Self::Port(port) => {
+                let any = SocketAddr::from(([0; 16], *port));
+                let socket = match Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)) {
+                    Ok(socket) => {
+                        socket.set_only_v6(false)?;
+                        socket
+                    }
+                    Err(_) => return bind_tcp(SocketAddr::from(([0; 4], *port))),
+                };
+
+                listen(socket, any)
+            }
+            Self::Address(addr) => bind_tcp(*addr),
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                        return Err(io::ErrorKind::AddrInUse.into());
+                    }
+                    fs::remove_file(path)?;
+                }
+
+                UnixListener::bind(path).map(Bound::Unix)
+            }
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<Bound> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    listen(socket, addr)
+}
+
+fn listen(socket: Socket, addr: SocketAddr) -> io::Result<Bound> {
+    // As `TcpListener::bind` does, so that a restarted agent can take its
+    // port back straight away.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into()).map(Bound::Tcp)
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Port(port) => write!(f, "{port}"),
+            Self::Address(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidEndpoint(String);
+
+impl fmt::Display for InvalidEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid endpoint `{}`, expected a port, an address such as `[::1]:9001`",
+            self.0
+        )?;
+        if cfg!(unix) {
+            f.write_str(" or `unix:<path>`")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidEndpoint {}
+
+impl FromStr for Endpoint {
+    type Err = InvalidEndpoint;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:")
+            && !path.is_empty()
+        {
+            return Ok(Self::Unix(path.into()));
+        }
+
+        s.parse()
+            .map(Self::Port)
+            .or_else(|_| s.parse().map(Self::Address))
+            .map_err(|_| InvalidEndpoint(s.to_owned()))
+    }
+}
+
+/// Parse comma separated endpoints, as the FFI takes them.
+pub fn parse_endpoints(list: &str) -> Result<Vec<Endpoint>, InvalidEndpoint> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|endpoint| !endpoint.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ok(Endpoint::Port(9001)), "9001".parse().map_err(drop));
+        assert_eq!(
+            Ok(Endpoint::Address(SocketAddr::from(([127, 0, 0, 1], 80)))),
+            "127.0.0.1:80".parse().map_err(drop)
+        );
+        assert_eq!(
+            Ok(Endpoint::Address(SocketAddr::from(([0; 16], 9001)))),
+            "[::]:9001".parse().map_err(drop)
+        );
+        assert!("::1".parse::<Endpoint>().is_err());
+        assert!("localhost:80".parse::<Endpoint>().is_err());
+        assert!("unix:".parse::<Endpoint>().is_err());
+
+        let endpoints = parse_endpoints("9001, [::1]:9002,").unwrap();
+        assert_eq!(
+            vec!["9001", "[::1]:9002"],
+            endpoints.iter().map(|e| e.to_string()).collect::<Vec<_>>()
+        );
+        assert!(parse_endpoints("9001,nope").is_err());
+    }
+
+    fn local_addr(bound: &Bound) -> SocketAddr {
+        match bound {
+            Bound::Tcp(listener) => listener.local_addr().unwrap(),
+            #[cfg(unix)]
+            Bound::Unix(_) => panic!("expected a TCP listener"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_tcp() {
+        // Any port, over both IPv4 and IPv6.
+        let bound = Endpoint::Port(0).bind().unwrap();
+        let port = local_addr(&bound).port();
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        TcpStream::connect(("::1", port)).await.unwrap();
+
+        // Only IPv6, leaving IPv4 to someone else.
+        let v6 = Endpoint::Address(SocketAddr::from(([0; 16], 0)))
+            .bind()
+            .unwrap();
+        let port = local_addr(&v6).port();
+        let _v4 = Endpoint::Address(SocketAddr::from(([0; 4], port)))
+            .bind()
+            .unwrap();
+
+        let taken = Endpoint::Address(SocketAddr::from(([127, 0, 0, 1], port)));
+        assert!(taken.bind().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let endpoint: Endpoint = format!("unix:{}", path.display()).parse().unwrap();
+        assert_eq!(Endpoint::Unix(path.clone()), endpoint);
+
+        let bound = endpoint.bind().unwrap();
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+
+        // Not while it's still listening, but once it's gone.
+        assert_eq!(
+            io::ErrorKind::AddrInUse,
+            endpoint.bind().err().unwrap().kind()
+        );
+        drop(bound);
+        let _bound = endpoint.bind().unwrap();
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+
+        // Anything else at the path is left alone.
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(Endpoint::Unix(file.clone()).bind().is_err());
+        assert!(file.exists());
+    }
+}
@@ -0,0 +1,411 @@
+//! Looking for files by digest, such as the indicators of compromise from an
+//! incident, without collecting anything else.
+//!
+//! Only files whose size is one of the targets' are read, so a hunt over a
+//! whole host hashes a small fraction of it.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, ensure};
+use filesystem_iter::{
+    file_offline::FileOffline, recursive_pattern, root_iterator_package, stream::root_stream,
+};
+use futures::{Stream, StreamExt};
+use log::debug;
+use serde::Serialize;
+
+use crate::{
+    digest::{Digest, DigestAlgorithm, Hashers},
+    known::{KnownHashes, algorithm_for_len, from_hex},
+    source::SourceFile,
+    stream::{EntryMetadata, StreamOptions},
+    throttle::spawn_reader,
+};
+
+/// The digests being hunted for, along with the sizes of the files they came
+/// from where those are known.
+#[derive(Debug)]
+pub struct Targets {
+    digests: Vec<KnownHashes>,
+    sizes: HashSet<u64>,
+    /// Some digest came without a size, so every file is a candidate.
+    any_size: bool,
+}
+
+impl Targets {
+    /// Parse a list with one target per line: a hex digest, optionally
+    /// followed by the file's size in bytes, separated by whitespace or a
+    /// comma. Lines starting with `#` are skipped.
+    ///
+    /// Digests are taken to be of the most common algorithm of their width,
+    /// unless `algorithm` says otherwise, so MD5, SHA-1 and SHA-256 targets
+    /// can be mixed.
+    pub fn parse(list: &str, algorithm: Option<DigestAlgorithm>) -> anyhow::Result<Self> {
+        let mut by_algorithm = BTreeMap::<DigestAlgorithm, Vec<Vec<u8>>>::new();
+        let mut sizes = HashSet::new();
+        let mut any_size = false;
+
+        for (index, line) in list.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|field| !field.is_empty());
+            let digest = fields
+                .next()
+                .and_then(from_hex)
+                .with_context(|| format!("line {number}: invalid digest"))?;
+            let algorithm = match algorithm {
+                Some(algorithm) => algorithm,
+                None => algorithm_for_len(digest.len()).with_context(|| {
+                    format!("line {number}: no digest is {} bytes", digest.len())
+                })?,
+            };
+
+            ensure!(
+                digest.len() == algorithm.digest_len(),
+                "line {number}: not a {algorithm} digest"
+            );
+
+            match fields.next() {
+                Some(size) => {
+                    let size = size
+                        .parse()
+                        .with_context(|| format!("line {number}: invalid size `{size}`"))?;
+                    sizes.insert(size);
+                }
+                None => any_size = true,
+            }
+
+            by_algorithm.entry(algorithm).or_default().push(digest);
+        }
+
+        ensure!(!by_algorithm.is_empty(), "there's nothing to hunt for");
+
+        let digests = by_algorithm
+            .into_iter()
+            .map(|(algorithm, digests)| KnownHashes::new(algorithm, digests))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            digests,
+            sizes,
+            any_size,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.digests.iter().map(KnownHashes::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn algorithms(&self) -> impl Iterator<Item = DigestAlgorithm> + '_ {
+        self.digests.iter().map(KnownHashes::algorithm)
+    }
+
+    /// Whether a file of `len` bytes could be one of the targets.
+    pub fn is_candidate(&self, len: u64) -> bool {
+        self.any_size || self.sizes.contains(&len)
+    }
+
+    /// The digests that are targets.
+    pub fn matches<'a>(&self, digests: &'a [Digest]) -> Vec<&'a Digest> {
+        digests
+            .iter()
+            .filter(|digest| self.digests.iter().any(|known| known.contains(digest)))
+            .collect()
+    }
+}
+
+/// A file found by a hunt, with everything known about it.
+#[derive(Debug, Clone, Serialize)]
+pub struct HuntMatch {
+    pub path: String,
+    pub size: u64,
+    /// Permission bits, in octal.
+    pub mode: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub atime: i64,
+    pub ctime: i64,
+    pub device: u64,
+    pub inode: u64,
+    pub links: u64,
+    /// Whether hashing the file left its access time alone.
+    pub atime_preserved: bool,
+    /// The target digests the file matched, as `<algorithm>:<hex>`.
+    pub matched: Vec<String>,
+    /// Every digest calculated for the file, by algorithm.
+    pub digests: BTreeMap<&'static str, String>,
+}
+
+/// Walk `roots` for the files matching `targets`, yielding them in no
+/// particular order as they're found. Files are hashed `options.concurrency`
+/// at a time, with the targets' algorithms and `options.digests`, and only
+/// if their size matches a target's. Files that can't be read are skipped.
+pub fn hunt(
+    roots: impl Iterator<Item = PathBuf> + Clone,
+    targets: Arc<Targets>,
+    options: StreamOptions,
+) -> anyhow::Result<impl Stream<Item = HuntMatch>> {
+    let patterns = roots.clone().map(recursive_pattern);
+    let package = root_iterator_package(roots, patterns)?;
+    let concurrency = options.concurrency.max(1);
+
+    let mut algorithms = options.digests.clone();
+    algorithms.extend(targets.algorithms());
+    algorithms.sort();
+    algorithms.dedup();
+
+    let options = Arc::new(options);
+
+    let candidates = {
+        let targets = targets.clone();
+        let options = options.clone();
+
+        root_stream(package).filter_map(move |entry| {
+            let candidate = entry.ok().filter(|entry| {
+                let meta = entry.metadata();
+                meta.is_file()
+                    && !meta.is_offline()
+                    && targets.is_candidate(meta.len())
+                    && options.is_included(entry.path())
+            });
+
+            async move { candidate }
+        })
+    };
+
+    let algorithms = Arc::new(algorithms);
+
+    let matches = candidates
+        .map(move |entry| {
+            let (targets, options, algorithms) =
+                (targets.clone(), options.clone(), algorithms.clone());
+
+            let path = entry.path().to_owned();
+            let reader = spawn_reader(
+                options.idle_io_priority,
+                options.low_cpu_priority,
+                move || {
+                    check(
+                        entry.path(),
+                        entry.metadata(),
+                        &targets,
+                        &algorithms,
+                        &options,
+                    )
+                },
+            );
+
+            async move {
+                match reader {
+                    Ok(reader) => reader.await,
+                    Err(err) => {
+                        debug!("Couldn't hash {}: {err}", path.display());
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(concurrency)
+        .filter_map(|result| async move { result.flatten() });
+
+    Ok(matches)
+}
+
+/// Hash one candidate, returning it if it's a match.
+fn check(
+    path: &Path,
+    meta: &Metadata,
+    targets: &Targets,
+    algorithms: &[DigestAlgorithm],
+    options: &StreamOptions,
+) -> Option<HuntMatch> {
+    let hash = || {
+        let mut file = SourceFile::open(path, options.direct_io)?;
+        let mut hashers = Hashers::new(algorithms);
+
+        loop {
+            let chunk = file.read_chunk(&options.throttle)?;
+
+            if chunk.is_empty() {
+                return Ok::<_, std::io::Error>((hashers.finalize(), file.atime_preserved()));
+            }
+
+            hashers.update(&chunk);
+        }
+    };
+
+    let (digests, atime_preserved) = hash()
+        .inspect_err(|err| debug!("Couldn't hash {}: {err}", path.display()))
+        .ok()?;
+
+    let matched: Vec<_> = targets
+        .matches(&digests)
+        .into_iter()
+        .map(|digest| format!("{}:{}", digest.algorithm, digest.hex()))
+        .collect();
+
+    if matched.is_empty() {
+        return None;
+    }
+
+    let metadata = EntryMetadata::from(meta);
+    let extra = ExtraMetadata::from(meta);
+
+    Some(HuntMatch {
+        path: path.to_string_lossy().into_owned(),
+        size: meta.len(),
+        mode: format!("{:04o}", metadata.mode),
+        uid: metadata.uid,
+        gid: metadata.gid,
+        mtime: metadata.mtime,
+        mtime_nsec: metadata.mtime_nsec,
+        atime: extra.atime,
+        ctime: extra.ctime,
+        device: extra.device,
+        inode: extra.inode,
+        links: extra.links,
+        atime_preserved,
+        matched,
+        digests: digests
+            .iter()
+            .map(|digest| (digest.algorithm.name(), digest.hex()))
+            .collect(),
+    })
+}
+
+/// What `EntryMetadata` leaves out.
+#[derive(Default)]
+struct ExtraMetadata {
+    atime: i64,
+    ctime: i64,
+    device: u64,
+    inode: u64,
+    links: u64,
+}
+
+impl From<&Metadata> for ExtraMetadata {
+    #[cfg(unix)]
+    fn from(meta: &Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            atime: meta.atime(),
+            ctime: meta.ctime(),
+            device: meta.dev(),
+            inode: meta.ino(),
+            links: meta.nlink(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from(meta: &Metadata) -> Self {
+        use std::time::UNIX_EPOCH;
+
+        let atime = meta
+            .accessed()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_secs() as i64);
+
+        Self {
+            atime,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn sha1(data: &[u8]) -> Digest {
+        let mut hashers = Hashers::new(&[DigestAlgorithm::Sha1]);
+        hashers.update(data);
+        hashers.finalize().remove(0)
+    }
+
+    #[test]
+    fn test_targets() {
+        let list = format!(
+            "# from the incident report\n{} 5\n{},7\n{}\n",
+            sha1(b"hello").hex(),
+            "ab".repeat(32),
+            "cd".repeat(16),
+        );
+        let targets = Targets::parse(&list, None).unwrap();
+
+        assert_eq!(3, targets.len());
+        assert_eq!(
+            vec![
+                DigestAlgorithm::Sha256,
+                DigestAlgorithm::Sha1,
+                DigestAlgorithm::Md5
+            ],
+            targets.algorithms().collect::<Vec<_>>()
+        );
+        // The MD5 target has no size, so nothing can be ruled out.
+        assert!(targets.is_candidate(1234));
+        assert_eq!(1, targets.matches(&[sha1(b"hello"), sha1(b"bye")]).len());
+
+        let sized = Targets::parse(&format!("{} 5", sha1(b"hello").hex()), None).unwrap();
+        assert!(sized.is_candidate(5));
+        assert!(!sized.is_candidate(6));
+
+        assert!(Targets::parse("# nothing\n", None).is_err());
+        assert!(Targets::parse(&format!("{} five", sha1(b"x").hex()), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hunt() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("tmp")).unwrap();
+        fs::write(root.path().join("tmp/dropper"), "evil").unwrap();
+        fs::write(root.path().join("copy"), "evil").unwrap();
+        fs::write(root.path().join("same-size"), "good").unwrap();
+        fs::write(root.path().join("other"), "harmless").unwrap();
+
+        let targets = Targets::parse(&format!("{} 4", sha1(b"evil").hex()), None).unwrap();
+        let roots = [root.path().to_owned()].into_iter();
+        let mut found: Vec<_> = hunt(roots, Arc::new(targets), StreamOptions::default())
+            .unwrap()
+            .collect()
+            .await;
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let paths: Vec<_> = found.iter().map(|found| Path::new(&found.path)).collect();
+        assert_eq!(
+            vec![root.path().join("copy"), root.path().join("tmp/dropper")],
+            paths
+        );
+
+        let dropper = &found[1];
+        assert_eq!(4, dropper.size);
+        assert_eq!(
+            vec![format!("sha1:{}", sha1(b"evil").hex())],
+            dropper.matched
+        );
+        // The default digests come along too.
+        assert!(dropper.digests.contains_key("sha256"));
+        assert!(dropper.digests.contains_key("md5"));
+    }
+}
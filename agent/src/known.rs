@@ -0,0 +1,370 @@
+//! Digests of files that aren't worth collecting, such as the stock binaries
+//! of an operating system.
+//!
+//! Lists can be given as plain text with one hex digest at the start of each
+//! line, as in `sha256sum` output, as NSRL-style CSV with a header row naming
+//! the digest columns, or in the compact binary form `KnownHashes::to_bytes`
+//! writes.
+
+use std::{cmp::Ordering, fmt};
+
+use anyhow::{Context, bail, ensure};
+
+use crate::digest::{Digest, DigestAlgorithm};
+
+/// Starts the binary form, followed by a version byte.
+const MAGIC: [u8; 4] = *b"AGKH";
+const VERSION: u8 = 1;
+
+/// Digests of one algorithm, kept as a single sorted run of fixed-width
+/// values and looked up by binary search.
+///
+/// This is exact, unlike a bloom filter, whose false positives would quietly
+/// leave files out of a collection.
+#[derive(Clone, PartialEq, Eq)]
+pub struct KnownHashes {
+    algorithm: DigestAlgorithm,
+    digests: Vec<u8>,
+}
+
+impl KnownHashes {
+    pub fn new(
+        algorithm: DigestAlgorithm,
+        digests: impl IntoIterator<Item = Vec<u8>>,
+    ) -> anyhow::Result<Self> {
+        let width = algorithm.digest_len();
+        let mut values = Vec::new();
+
+        for digest in digests {
+            ensure!(
+                digest.len() == width,
+                "a {algorithm} digest is {width} bytes, not {}",
+                digest.len()
+            );
+            values.push(digest);
+        }
+
+        values.sort_unstable();
+        values.dedup();
+
+        Ok(Self {
+            algorithm,
+            digests: values.concat(),
+        })
+    }
+
+    /// Parse a list in any of the supported forms. Plain lists are assumed to
+    /// hold the most common algorithm of their digests' width unless told
+    /// otherwise, and CSV lists use the strongest column they have.
+    pub fn parse(input: &[u8], algorithm: Option<DigestAlgorithm>) -> anyhow::Result<Self> {
+        if input.starts_with(&MAGIC) {
+            let known = Self::from_bytes(input)?;
+
+            if let Some(algorithm) = algorithm {
+                ensure!(
+                    known.algorithm == algorithm,
+                    "the list holds {} digests, not {algorithm}",
+                    known.algorithm
+                );
+            }
+
+            return Ok(known);
+        }
+
+        let text = str::from_utf8(input).context("a known-file list has to be text")?;
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .peekable();
+
+        let Some(&(_, first)) = lines.peek() else {
+            bail!("the known-file list is empty");
+        };
+
+        if first.starts_with('"') || first.contains(',') {
+            parse_csv(lines, algorithm)
+        } else {
+            parse_plain(lines, algorithm)
+        }
+    }
+
+    /// The compact binary form: magic, version, the algorithm's name, the
+    /// number of digests as a little-endian `u64`, then the sorted digests.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let name = self.algorithm.name();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 10 + name.len() + self.digests.len());
+
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&(self.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.digests);
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let truncated = || anyhow::anyhow!("the known-file list is truncated");
+
+        let rest = &bytes[MAGIC.len()..];
+        let (&version, rest) = rest.split_first().ok_or_else(truncated)?;
+        ensure!(
+            version == VERSION,
+            "unsupported known-file list version {version}"
+        );
+
+        let (&name_len, rest) = rest.split_first().ok_or_else(truncated)?;
+        let (name, rest) = rest
+            .split_at_checked(name_len.into())
+            .ok_or_else(truncated)?;
+        let algorithm: DigestAlgorithm = str::from_utf8(name)?.parse()?;
+
+        let (count, digests) = rest.split_at_checked(8).ok_or_else(truncated)?;
+        let count = u64::from_le_bytes(count.try_into().unwrap());
+        let width = algorithm.digest_len();
+
+        ensure!(
+            digests.len() as u64 == count.saturating_mul(width as u64),
+            "the known-file list should hold {count} digests, but has {} bytes of them",
+            digests.len()
+        );
+        ensure!(
+            digests
+                .chunks_exact(width)
+                .zip(digests.chunks_exact(width).skip(1))
+                .all(|(a, b)| a < b),
+            "the known-file list isn't sorted"
+        );
+
+        Ok(Self {
+            algorithm,
+            digests: digests.to_vec(),
+        })
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        self.algorithm
+    }
+
+    pub fn len(&self) -> usize {
+        self.digests.len() / self.algorithm.digest_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+
+    pub fn contains(&self, digest: &Digest) -> bool {
+        let width = self.algorithm.digest_len();
+
+        if digest.algorithm != self.algorithm || digest.value.len() != width {
+            return false;
+        }
+
+        // Binary search over the fixed-width values, in place.
+        let (mut low, mut high) = (0, self.len());
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let value = &self.digests[middle * width..][..width];
+
+            match value.cmp(&digest.value) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return true,
+            }
+        }
+
+        false
+    }
+}
+
+impl fmt::Debug for KnownHashes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KnownHashes")
+            .field("algorithm", &self.algorithm)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+fn parse_plain<'a>(
+    lines: impl Iterator<Item = (usize, &'a str)>,
+    algorithm: Option<DigestAlgorithm>,
+) -> anyhow::Result<KnownHashes> {
+    let mut chosen = algorithm;
+    let mut digests = Vec::new();
+
+    for (number, line) in lines {
+        let hex = line.split_whitespace().next().unwrap_or_default();
+        let digest = from_hex(hex).with_context(|| format!("line {number}: invalid digest"))?;
+        let algorithm =
+            match chosen {
+                Some(algorithm) => algorithm,
+                None => *chosen.insert(algorithm_for_len(digest.len()).with_context(|| {
+                    format!("line {number}: no digest is {} bytes", digest.len())
+                })?),
+            };
+
+        ensure!(
+            digest.len() == algorithm.digest_len(),
+            "line {number}: not a {algorithm} digest"
+        );
+        digests.push(digest);
+    }
+
+    KnownHashes::new(chosen.unwrap_or(DigestAlgorithm::Sha256), digests)
+}
+
+fn parse_csv<'a>(
+    mut lines: impl Iterator<Item = (usize, &'a str)>,
+    algorithm: Option<DigestAlgorithm>,
+) -> anyhow::Result<KnownHashes> {
+    let (_, header) = lines.next().unwrap();
+    let columns: Vec<String> = header
+        .split(',')
+        .map(|column| {
+            column
+                .trim()
+                .trim_matches('"')
+                .replace('-', "")
+                .to_lowercase()
+        })
+        .collect();
+    let column_of = |algorithm: DigestAlgorithm| {
+        let name = algorithm.name().replace('-', "");
+        columns.iter().position(|column| *column == name)
+    };
+
+    let (algorithm, column) = match algorithm {
+        Some(algorithm) => (
+            algorithm,
+            column_of(algorithm).with_context(|| format!("the list has no {algorithm} column"))?,
+        ),
+        None => DigestAlgorithm::ALL
+            .into_iter()
+            .find_map(|algorithm| Some((algorithm, column_of(algorithm)?)))
+            .context("the list's header names no digest columns")?,
+    };
+
+    let mut digests = Vec::new();
+
+    for (number, line) in lines {
+        // Only the columns up to the digest are split, so commas in later
+        // ones such as file names don't matter.
+        let field = line
+            .splitn(column + 2, ',')
+            .nth(column)
+            .with_context(|| format!("line {number}: missing the {algorithm} column"))?;
+        let digest = from_hex(field.trim().trim_matches('"'))
+            .with_context(|| format!("line {number}: invalid digest"))?;
+
+        digests.push(digest);
+    }
+
+    KnownHashes::new(algorithm, digests)
+}
+
+/// Plain lists don't say what their digests are, so go by length.
+pub(crate) fn algorithm_for_len(len: usize) -> Option<DigestAlgorithm> {
+    [
+        DigestAlgorithm::Md5,
+        DigestAlgorithm::Sha1,
+        DigestAlgorithm::Sha256,
+    ]
+    .into_iter()
+    .find(|algorithm| algorithm.digest_len() == len)
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: DigestAlgorithm, hex: &str) -> Digest {
+        Digest {
+            algorithm,
+            value: from_hex(hex).unwrap(),
+        }
+    }
+
+    const EMPTY_SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+    const EMPTY_MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_plain_list() {
+        let list = format!(
+            "# stock binaries\n{EMPTY_SHA256}  /usr/bin/true\n\n{}\n",
+            "00".repeat(32)
+        );
+        let known = KnownHashes::parse(list.as_bytes(), None).unwrap();
+
+        assert_eq!(DigestAlgorithm::Sha256, known.algorithm());
+        assert_eq!(2, known.len());
+        assert!(known.contains(&digest(DigestAlgorithm::Sha256, EMPTY_SHA256)));
+        assert!(!known.contains(&digest(DigestAlgorithm::Sha256, &"11".repeat(32))));
+        // The same bytes from another algorithm aren't a match.
+        assert!(!known.contains(&digest(DigestAlgorithm::Blake3, EMPTY_SHA256)));
+
+        let blake3 = KnownHashes::parse(list.as_bytes(), Some(DigestAlgorithm::Blake3)).unwrap();
+        assert!(blake3.contains(&digest(DigestAlgorithm::Blake3, EMPTY_SHA256)));
+
+        assert!(KnownHashes::parse(b"xyz\n", None).is_err());
+        assert!(KnownHashes::parse(format!("{EMPTY_MD5}\n{EMPTY_SHA1}").as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn test_nsrl_list() {
+        let list = format!(
+            "\"SHA-1\",\"MD5\",\"CRC32\",\"FileName\",\"FileSize\"\n\
+             \"{}\",\"{EMPTY_MD5}\",\"00000000\",\"empty, really\",0\n\
+             \"{}\",\"{}\",\"00000000\",\"other\",1\n",
+            EMPTY_SHA1.to_uppercase(),
+            "ab".repeat(20),
+            "cd".repeat(16),
+        );
+
+        let known = KnownHashes::parse(list.as_bytes(), None).unwrap();
+        assert_eq!(DigestAlgorithm::Sha1, known.algorithm());
+        assert!(known.contains(&digest(DigestAlgorithm::Sha1, EMPTY_SHA1)));
+
+        let md5 = KnownHashes::parse(list.as_bytes(), Some(DigestAlgorithm::Md5)).unwrap();
+        assert!(md5.contains(&digest(DigestAlgorithm::Md5, EMPTY_MD5)));
+
+        assert!(KnownHashes::parse(list.as_bytes(), Some(DigestAlgorithm::Blake3)).is_err());
+    }
+
+    #[test]
+    fn test_binary_list() {
+        let values = (0..1000u32).map(|n| blake3::hash(&n.to_le_bytes()).as_bytes().to_vec());
+        let known = KnownHashes::new(DigestAlgorithm::Blake3, values.clone()).unwrap();
+        let bytes = known.to_bytes();
+
+        let loaded = KnownHashes::parse(&bytes, None).unwrap();
+        assert_eq!(known, loaded);
+
+        for value in values {
+            assert!(loaded.contains(&Digest {
+                algorithm: DigestAlgorithm::Blake3,
+                value,
+            }));
+        }
+
+        assert!(KnownHashes::parse(&bytes[..bytes.len() - 1], None).is_err());
+        assert!(KnownHashes::parse(&bytes, Some(DigestAlgorithm::Sha256)).is_err());
+    }
+}
@@ -1,13 +1,158 @@
-mod stream;
-mod web_service;
+use std::{
+    ffi::{CStr, c_char, c_int},
+    fmt, ptr,
+};
 
+use auth::Auth;
+use endpoint::{DEFAULT_PORT, Endpoint};
+use log::error;
+use stream::StreamOptions;
+
+pub mod auth;
+pub mod baseline;
+pub mod chunking;
+pub mod codec;
+pub mod digest;
+pub mod encryption;
+pub mod endpoint;
+pub mod hunt;
+pub mod known;
+pub mod mode;
+pub mod signing;
+pub mod source;
+pub mod stream;
+pub mod throttle;
+pub mod tls;
+pub mod web_service;
+
+/// `start_with_options` was given recipients it can't parse.
+pub const START_INVALID_RECIPIENTS: c_int = 1;
+/// `start_with_options` was given tokens it can't parse.
+pub const START_INVALID_TOKENS: c_int = 2;
+/// `start_with_options` was given endpoints it can't parse.
+pub const START_INVALID_ENDPOINTS: c_int = 3;
+/// The service couldn't be started, e.g. because an endpoint was taken, or
+/// stopped serving.
+pub const START_FAILED: c_int = 4;
+
+/// Serve the whole filesystem on port 9001, to anyone. Only returns if the
+/// service fails, which `start_with_options` can say more about.
 #[unsafe(no_mangle)]
 pub extern "C" fn start() {
-    tokio::runtime::Builder::new_multi_thread()
+    unsafe { start_with_options(ptr::null(), ptr::null(), ptr::null()) };
+}
+
+/// Serve the whole filesystem as `start` does, but with a say in how. Only
+/// returns if the service fails, with one of the `START_` error codes, and
+/// the error logged.
+///
+/// # Safety
+///
+/// `recipients` must be null, or a NUL-terminated string of comma separated
+/// hex X25519 public keys to encrypt every collection to.
+///
+/// `tokens` must be null, letting anyone in, or a NUL-terminated string of
+/// the static tokens callers must present, one per line as `Auth::new`
+/// takes them. Signed tokens aren't accepted here, since there's no way to
+/// pass their key; `agent serve --token-key` takes those.
+///
+/// `endpoints` must be null, or a NUL-terminated string of comma separated
+/// endpoints to listen on, each a port, an address such as `[::1]:9001`, or
+/// `unix:<path>`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn start_with_options(
+    recipients: *const c_char,
+    tokens: *const c_char,
+    endpoints: *const c_char,
+) -> c_int {
+    let recipients = match unsafe { c_string(recipients) } {
+        None => Vec::new(),
+        Some(recipients) => match encryption::parse_recipients(&recipients) {
+            Ok(recipients) => recipients,
+            Err(err) => return failed(START_INVALID_RECIPIENTS, err),
+        },
+    };
+
+    let auth = match unsafe { c_string(tokens) } {
+        None => Auth::default(),
+        Some(tokens) => match Auth::new(Some(&tokens), None) {
+            Ok(auth) => auth,
+            Err(err) => return failed(START_INVALID_TOKENS, err),
+        },
+    };
+
+    let endpoints = match unsafe { c_string(endpoints) } {
+        None => vec![Endpoint::Port(DEFAULT_PORT)],
+        Some(endpoints) => match endpoint::parse_endpoints(&endpoints) {
+            Ok(endpoints) => endpoints,
+            Err(err) => return failed(START_INVALID_ENDPOINTS, err),
+        },
+    };
+
+    let options = StreamOptions {
+        recipients,
+        ..Default::default()
+    };
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(async {
-            web_service::start(9001, &["/"]).await.unwrap();
-        })
+    {
+        Ok(runtime) => runtime,
+        Err(err) => return failed(START_FAILED, err),
+    };
+
+    match runtime.block_on(web_service::start(&endpoints, &["/"], options, None, auth)) {
+        Ok(()) => failed(START_FAILED, "the service stopped"),
+        Err(err) => failed(START_FAILED, format!("{err:#}")),
+    }
+}
+
+/// A string passed across the FFI, unless it's null.
+///
+/// # Safety
+///
+/// `string` must be null or NUL-terminated.
+unsafe fn c_string(string: *const c_char) -> Option<String> {
+    (!string.is_null()).then(|| {
+        let string = unsafe { CStr::from_ptr(string) };
+        string.to_string_lossy().into_owned()
+    })
+}
+
+/// Log why `start_with_options` is giving up, and return `code`.
+fn failed(code: c_int, err: impl fmt::Display) -> c_int {
+    error!("Failed to serve: {err}");
+
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, net::TcpListener};
+
+    use super::*;
+
+    fn start(recipients: Option<&str>, endpoints: &str) -> c_int {
+        let recipients = recipients.map(|recipients| CString::new(recipients).unwrap());
+        let endpoints = CString::new(endpoints).unwrap();
+
+        unsafe {
+            start_with_options(
+                recipients.as_ref().map_or(ptr::null(), |r| r.as_ptr()),
+                ptr::null(),
+                endpoints.as_ptr(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_start_errors() {
+        assert_eq!(START_INVALID_ENDPOINTS, start(None, "nope"));
+        assert_eq!(START_INVALID_RECIPIENTS, start(Some("nope"), "9001"));
+
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = taken.local_addr().unwrap().to_string();
+        assert_eq!(START_FAILED, start(None, &endpoint));
+    }
 }
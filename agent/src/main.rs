@@ -1,10 +1,34 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    pin::pin,
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::{Parser, Subcommand};
+use agentfs::{
+    auth::{Auth, Caller, MIN_KEY_LEN, Scope, mint},
+    codec::Compression,
+    digest::DigestAlgorithm,
+    encryption::Recipient,
+    endpoint::Endpoint,
+    hunt::{Targets, hunt},
+    known::KnownHashes,
+    mode::{ModeRule, ModeRules},
+    signing::SigningKey,
+    stream::StreamOptions,
+    throttle::{Limits, Throttle},
+    tls::{Certificate, server_config},
+    web_service,
+};
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use futures::StreamExt;
+use log::{info, warn};
+use rustls::ServerConfig;
 
-mod stream;
 mod sys_info;
-mod web_service;
 
 /// Get data from the system
 #[derive(Debug, Parser)]
@@ -32,15 +56,252 @@ enum Commands {
 
     /// Start the web service
     Serve {
-        /// Web service port
-        port: u16,
+        /// Where to listen: a port on every interface, IPv4 and IPv6 alike, an
+        /// address such as `127.0.0.1:9001` or `[::1]:9001`, or `unix:<path>`
+        endpoint: Endpoint,
 
         /// Root file paths
         #[clap(required = true)]
         root: Vec<PathBuf>,
+
+        /// Also listen here, written the same way; can be given more than once
+        #[clap(long)]
+        listen: Vec<Endpoint>,
+
+        /// Digests to put in every file footer, unless a request asks for others
+        #[clap(long, value_delimiter = ',', default_value = "sha256,md5")]
+        digests: Vec<DigestAlgorithm>,
+
+        /// How to compress file contents: `store`, `lz4`, `zstd` or `zstd:<level>`
+        #[clap(long, default_value = "lz4")]
+        compression: Compression,
+
+        /// How many files to read, hash and compress at once (defaults to the CPU count)
+        #[clap(long)]
+        concurrency: Option<usize>,
+
+        /// MiB of encoded data allowed to queue up ahead of the network
+        #[clap(long, default_value_t = 64)]
+        memory_budget: usize,
+
+        /// Bytes per second to send at most, adjustable later through `/throttle`
+        #[clap(long)]
+        send_limit: Option<u64>,
+
+        /// Bytes per second to read from disk at most
+        #[clap(long)]
+        read_limit: Option<u64>,
+
+        /// Read calls per second to make at most
+        #[clap(long)]
+        read_iops: Option<u64>,
+
+        /// Read files at idle I/O priority (Linux only)
+        #[clap(long)]
+        idle_io: bool,
+
+        /// Read, hash and compress at the lowest CPU priority (Linux only)
+        #[clap(long)]
+        low_cpu: bool,
+
+        /// Read files with O_DIRECT instead of dropping them from the page cache after (Linux only)
+        #[clap(long)]
+        direct_io: bool,
+
+        /// `metadata`, `hash` or `full`, for the files matching a pattern as
+        /// `<mode>:<pattern>`, or for everything else; the first matching pattern wins
+        #[clap(long = "mode")]
+        modes: Vec<ModeRule>,
+
+        /// Hashes of known files to send as such rather than in full, as a plain,
+        /// NSRL-style or packed list
+        #[clap(long)]
+        known: Option<PathBuf>,
+
+        /// The algorithm of the `--known` list's digests, when it can't tell
+        #[clap(long)]
+        known_algorithm: Option<DigestAlgorithm>,
+
+        /// Split files into content-defined chunks and send repeated chunks only once,
+        /// unless a request says otherwise
+        #[clap(long)]
+        dedup: bool,
+
+        /// Walk each directory's entries sorted by name, so that captures of the same
+        /// tree come out in the same order, unless a request says otherwise
+        #[clap(long)]
+        sorted: bool,
+
+        /// Hex X25519 public key to encrypt every collection to, any of which can decrypt
+        /// it; may be repeated
+        #[clap(long = "recipient")]
+        recipients: Vec<Recipient>,
+
+        /// File holding the hex Ed25519 key to sign every collection with, as written
+        /// by `keygen`
+        #[clap(long)]
+        signing_key: Option<PathBuf>,
+
+        #[command(flatten)]
+        tls: Box<TlsArgs>,
+
+        #[command(flatten)]
+        auth: Box<AuthArgs>,
+    },
+
+    /// Find the files matching a list of target digests, printing each as a
+    /// line of JSON
+    Hunt {
+        /// Targets, one per line as a hex digest optionally followed by the file's size
+        targets: PathBuf,
+
+        /// Root file paths
+        #[clap(required = true)]
+        root: Vec<PathBuf>,
+
+        /// The algorithm of the targets' digests, when their length can't tell
+        #[clap(long)]
+        algorithm: Option<DigestAlgorithm>,
+
+        /// Digests to report for every match, besides the targets' own
+        #[clap(long, value_delimiter = ',', default_value = "sha256,md5")]
+        digests: Vec<DigestAlgorithm>,
+
+        /// How many files to hash at once (defaults to the CPU count)
+        #[clap(long)]
+        concurrency: Option<usize>,
+    },
+
+    /// Pack a plain or NSRL-style list of known file hashes into the compact
+    /// form the web service loads fastest
+    PackKnown {
+        /// The list to pack
+        input: PathBuf,
+
+        /// Where to write the packed list
+        output: PathBuf,
+
+        /// The algorithm of the list's digests, when it can't tell
+        #[clap(long)]
+        algorithm: Option<DigestAlgorithm>,
+    },
+
+    /// Mint a token for `serve --token-key`, valid for a while and optionally
+    /// limited in what it may collect
+    Token {
+        /// File holding the key, the same as `serve` was given
+        key: PathBuf,
+
+        /// Who the token is for, as it appears in the agent's log
+        name: String,
+
+        /// How many seconds the token works for
+        #[clap(long, default_value_t = 3600)]
+        valid_for: u64,
+
+        /// Only allow collecting under this path; may be repeated
+        #[clap(long = "root")]
+        roots: Vec<PathBuf>,
+
+        /// Only allow collecting what matches this glob pattern; may be repeated
+        #[clap(long = "pattern")]
+        patterns: Vec<String>,
+
+        /// Allow changing the throttle and the known-file list, even with a
+        /// `--root` or `--pattern`
+        #[clap(long)]
+        admin: bool,
+    },
+
+    /// Generate a key for `serve --signing-key`, printing the public key that
+    /// the decoder reports as the signer
+    Keygen {
+        /// Where to write the key
+        output: PathBuf,
     },
 }
 
+/// How `serve` does HTTPS, if it does.
+#[derive(Debug, Args)]
+struct TlsArgs {
+    /// PEM certificate chain to serve HTTPS with, leaf first
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with a new self-signed certificate, logging its fingerprint for
+    /// clients to pin
+    #[clap(long, conflicts_with = "tls_cert")]
+    tls_self_signed: bool,
+
+    /// PEM CA certificates, only clients with a certificate issued by one of which
+    /// may connect
+    #[clap(long)]
+    client_ca: Option<PathBuf>,
+}
+
+impl TlsArgs {
+    fn server_config(self) -> anyhow::Result<Option<Arc<ServerConfig>>> {
+        let certificate = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Certificate::load(&cert, &key)?,
+            _ if self.tls_self_signed => Certificate::self_signed()?,
+            _ if self.client_ca.is_some() => {
+                anyhow::bail!("`--client-ca` needs `--tls-cert` or `--tls-self-signed`")
+            }
+            _ => return Ok(None),
+        };
+
+        info!(
+            "TLS certificate SHA-256 fingerprint: {}",
+            certificate.fingerprint()
+        );
+
+        server_config(certificate, self.client_ca.as_deref()).map(Some)
+    }
+}
+
+/// Who `serve` lets in, if not everyone.
+#[derive(Debug, Args)]
+struct AuthArgs {
+    /// File of static bearer tokens, one per line as `<name> <token>`, optionally
+    /// followed by `root:<path>` and `pattern:<glob>` limiting what it may collect,
+    /// and `admin` letting it change the throttle and known-file list regardless
+    #[clap(long)]
+    tokens: Option<PathBuf>,
+
+    /// File holding the key of signed, time-limited tokens, as minted by `token`
+    #[clap(long)]
+    token_key: Option<PathBuf>,
+}
+
+impl AuthArgs {
+    fn auth(self) -> anyhow::Result<Auth> {
+        let tokens = self
+            .tokens
+            .map(|path| fs::read_to_string(&path).with_context(|| format!("{}", path.display())))
+            .transpose()?;
+        let key = self.token_key.map(read_token_key).transpose()?;
+
+        let auth = Auth::new(tokens.as_deref(), key)?;
+        if auth.is_open() {
+            warn!("Without `--tokens` or `--token-key`, anyone who can connect can collect");
+        }
+
+        Ok(auth)
+    }
+}
+
+/// A key for signed tokens: whatever the file holds, less surrounding
+/// whitespace, e.g. from `openssl rand -hex 32`.
+fn read_token_key(path: PathBuf) -> anyhow::Result<Vec<u8>> {
+    let key = fs::read(&path).with_context(|| format!("{}", path.display()))?;
+
+    Ok(key.trim_ascii().to_vec())
+}
+
 #[derive(Debug, Subcommand)]
 enum FilesCommands {
     /// Recursively list all files from a specified root
@@ -55,10 +316,22 @@ enum FilesCommands {
 async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
 
+    // Hunt results go to stdout, so keep the log out of their way.
+    let terminal_mode = match args.command {
+        Commands::Hunt { .. } => simplelog::TerminalMode::Stderr,
+        _ => simplelog::TerminalMode::Mixed,
+    };
+
+    // Every TLS handshake would otherwise fill screens, and failed ones are
+    // logged by the listener anyway.
+    let config = simplelog::ConfigBuilder::new()
+        .add_filter_ignore_str("rustls")
+        .build();
+
     simplelog::CombinedLogger::init(vec![simplelog::TermLogger::new(
         log::LevelFilter::Trace,
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Mixed,
+        config,
+        terminal_mode,
         simplelog::ColorChoice::Auto,
     )])?;
 
@@ -83,7 +356,141 @@ async fn main() -> anyhow::Result<()> {
 
             // println!("{}", humanize_bytes_decimal!(size));
         }
-        Commands::Serve { port, root } => web_service::start(port, root).await?,
+        Commands::Serve {
+            endpoint,
+            root,
+            listen,
+            digests,
+            compression,
+            concurrency,
+            memory_budget,
+            send_limit,
+            read_limit,
+            read_iops,
+            idle_io,
+            low_cpu,
+            direct_io,
+            modes,
+            known,
+            known_algorithm,
+            dedup,
+            sorted,
+            recipients,
+            signing_key,
+            tls,
+            auth,
+        } => {
+            let known = known
+                .map(|path| KnownHashes::parse(&fs::read(path)?, known_algorithm))
+                .transpose()?;
+            let signing_key = signing_key
+                .map(|path| {
+                    fs::read_to_string(&path)?
+                        .parse::<SigningKey>()
+                        .with_context(|| format!("{}: invalid signing key", path.display()))
+                })
+                .transpose()?;
+
+            let tls = tls.server_config()?;
+            let auth = auth.auth()?;
+
+            let limits = Limits {
+                send_bytes_per_sec: send_limit,
+                read_bytes_per_sec: read_limit,
+                read_ops_per_sec: read_iops,
+            };
+            let defaults = StreamOptions::default();
+            let options = StreamOptions {
+                digests,
+                compression,
+                concurrency: concurrency.unwrap_or(defaults.concurrency),
+                memory_budget: memory_budget * 1024 * 1024,
+                throttle: Arc::new(Throttle::new(limits)),
+                idle_io_priority: idle_io,
+                low_cpu_priority: low_cpu,
+                direct_io,
+                modes: ModeRules::new(modes)?,
+                known: known.map(Arc::new),
+                dedup,
+                sorted,
+                recipients,
+                signing_key: signing_key.map(Arc::new),
+                ..defaults
+            };
+
+            let endpoints: Vec<_> = [endpoint].into_iter().chain(listen).collect();
+            web_service::start(&endpoints, root, options, tls, auth).await?
+        }
+        Commands::Hunt {
+            targets,
+            root,
+            algorithm,
+            digests,
+            concurrency,
+        } => {
+            let targets = Targets::parse(&fs::read_to_string(targets)?, algorithm)?;
+            let defaults = StreamOptions::default();
+            let options = StreamOptions {
+                digests,
+                concurrency: concurrency.unwrap_or(defaults.concurrency),
+                ..defaults
+            };
+
+            let mut matches = pin!(hunt(root.into_iter(), Arc::new(targets), options)?);
+            let mut stdout = io::stdout().lock();
+
+            while let Some(found) = matches.next().await {
+                serde_json::to_writer(&mut stdout, &found)?;
+                writeln!(stdout)?;
+            }
+        }
+        Commands::PackKnown {
+            input,
+            output,
+            algorithm,
+        } => {
+            let known = KnownHashes::parse(&fs::read(input)?, algorithm)?;
+            fs::write(output, known.to_bytes())?;
+
+            println!("{} {} digests", known.len(), known.algorithm());
+        }
+        Commands::Token {
+            key,
+            name,
+            valid_for,
+            roots,
+            patterns,
+            admin,
+        } => {
+            let key = read_token_key(key)?;
+            anyhow::ensure!(
+                key.len() >= MIN_KEY_LEN,
+                "the key must be at least {MIN_KEY_LEN} bytes"
+            );
+            let scope = Scope {
+                roots,
+                patterns,
+                admin,
+            };
+            // The agent would turn away a token it can't make sense of.
+            Caller::new(name.clone(), scope.clone())?;
+
+            println!(
+                "{}",
+                mint(&key, &name, Duration::from_secs(valid_for), scope)
+            );
+        }
+        Commands::Keygen { output } => {
+            let key = SigningKey::generate();
+
+            let mut file = fs::File::options();
+            file.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+
+            writeln!(file.open(output)?, "{key}")?;
+            println!("{}", key.signer());
+        }
     }
 
     Ok(())
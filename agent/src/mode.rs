@@ -0,0 +1,245 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use filesystem_iter::{GlobSet, glob_set};
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// How much of a file a collection captures.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[rkyv(derive(Debug, Clone, Copy, PartialEq, Eq))]
+pub enum CollectionMode {
+    /// The entry alone, without opening the file.
+    Metadata,
+    /// The entry and the digests of its contents, without the contents.
+    Hash,
+    /// Everything.
+    #[default]
+    Full,
+    /// The entry and the digest that matched a list of known files. Never
+    /// asked for, but what `Hash` and `Full` files become on a match.
+    Known,
+    /// The entry alone, of a file that hasn't changed since the baseline it
+    /// was collected against. Never asked for either.
+    Unchanged,
+}
+
+impl CollectionMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Metadata => "metadata",
+            Self::Hash => "hash",
+            Self::Full => "full",
+            Self::Known => "known",
+            Self::Unchanged => "unchanged",
+        }
+    }
+
+    /// Whether a `FileFooter` follows the file's header, or nothing does.
+    pub fn has_footer(self) -> bool {
+        !matches!(self, Self::Metadata | Self::Unchanged)
+    }
+}
+
+impl From<ArchivedCollectionMode> for CollectionMode {
+    fn from(archived: ArchivedCollectionMode) -> Self {
+        match archived {
+            ArchivedCollectionMode::Metadata => Self::Metadata,
+            ArchivedCollectionMode::Hash => Self::Hash,
+            ArchivedCollectionMode::Full => Self::Full,
+            ArchivedCollectionMode::Known => Self::Known,
+            ArchivedCollectionMode::Unchanged => Self::Unchanged,
+        }
+    }
+}
+
+impl fmt::Display for CollectionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownMode(String);
+
+impl fmt::Display for UnknownMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown collection mode `{}`, expected `metadata`, `hash` or `full`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownMode {}
+
+impl FromStr for CollectionMode {
+    type Err = UnknownMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Metadata, Self::Hash, Self::Full]
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| UnknownMode(s.to_owned()))
+    }
+}
+
+/// A mode for the files matching a glob pattern, written `<mode>:<pattern>`,
+/// or for every other file when written as just `<mode>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeRule {
+    pub mode: CollectionMode,
+    pub pattern: Option<String>,
+}
+
+impl FromStr for ModeRule {
+    type Err = UnknownMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, pattern) = match s.split_once(':') {
+            Some((mode, pattern)) => (mode, Some(pattern.to_owned())),
+            None => (s, None),
+        };
+
+        Ok(Self {
+            mode: mode.parse()?,
+            pattern,
+        })
+    }
+}
+
+/// Glob patterns, along with how they were written.
+#[derive(Debug, Clone, Default)]
+pub struct PatternSet {
+    set: GlobSet,
+    sources: Vec<String>,
+}
+
+impl PatternSet {
+    pub fn new(patterns: Vec<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            set: glob_set(&patterns)?,
+            sources: patterns,
+        })
+    }
+
+    /// The patterns, as written.
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.set.is_match(path)
+    }
+
+    /// The index of the first pattern matching `path`.
+    fn first_match(&self, path: &Path) -> Option<usize> {
+        self.set.matches(path).into_iter().min()
+    }
+}
+
+/// Picks the mode for each file from a list of rules, where the first
+/// pattern to match wins. The rules only choose between files the walk
+/// finds; they don't add anything to it.
+#[derive(Debug, Clone, Default)]
+pub struct ModeRules {
+    default: CollectionMode,
+    modes: Vec<CollectionMode>,
+    patterns: PatternSet,
+}
+
+impl ModeRules {
+    /// Build the rules, in order. The last rule without a pattern sets the
+    /// mode for files no pattern matches, which is otherwise `full`.
+    pub fn new(rules: impl IntoIterator<Item = ModeRule>) -> anyhow::Result<Self> {
+        let mut default = CollectionMode::default();
+        let mut modes = Vec::new();
+        let mut patterns = Vec::new();
+
+        for rule in rules {
+            match rule.pattern {
+                Some(pattern) => {
+                    modes.push(rule.mode);
+                    patterns.push(pattern);
+                }
+                None => default = rule.mode,
+            }
+        }
+
+        Ok(Self {
+            default,
+            modes,
+            patterns: PatternSet::new(patterns)?,
+        })
+    }
+
+    /// The rules in effect, written as `new` takes them, with the mode for
+    /// everything else last.
+    pub fn describe(&self) -> Vec<String> {
+        self.modes
+            .iter()
+            .zip(self.patterns.sources())
+            .map(|(mode, pattern)| format!("{mode}:{pattern}"))
+            .chain([self.default.to_string()])
+            .collect()
+    }
+
+    pub fn mode_for(&self, path: &Path) -> CollectionMode {
+        self.patterns
+            .first_match(path)
+            .map_or(self.default, |index| self.modes[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_rules() {
+        let rules = [
+            "full:/etc/**",
+            "hash:/usr/**",
+            "metadata:/usr/share/**",
+            "metadata",
+        ];
+        let rules = ModeRules::new(rules.map(|rule| rule.parse().unwrap())).unwrap();
+
+        assert_eq!(
+            CollectionMode::Full,
+            rules.mode_for(Path::new("/etc/passwd"))
+        );
+        assert_eq!(
+            CollectionMode::Hash,
+            rules.mode_for(Path::new("/usr/bin/ls"))
+        );
+        // The earlier, broader rule wins.
+        assert_eq!(
+            CollectionMode::Hash,
+            rules.mode_for(Path::new("/usr/share/doc/README"))
+        );
+        assert_eq!(
+            CollectionMode::Metadata,
+            rules.mode_for(Path::new("/var/log/syslog"))
+        );
+        assert_eq!(
+            rules.describe(),
+            [
+                "full:/etc/**",
+                "hash:/usr/**",
+                "metadata:/usr/share/**",
+                "metadata"
+            ]
+        );
+
+        let everything = ModeRules::default();
+        assert_eq!(
+            CollectionMode::Full,
+            everything.mode_for(Path::new("/etc/passwd"))
+        );
+        assert_eq!(everything.describe(), ["full"]);
+
+        assert!("partial:/etc/**".parse::<ModeRule>().is_err());
+        assert!(PatternSet::new(vec!["/usr/[".to_owned()]).is_err());
+        assert!(ModeRules::new(["hash:/usr/[".parse().unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Signatures proving which agent produced a stream, and that it hasn't been
+//! altered since.
+//!
+//! Every byte of the framed stream is hashed in order, chaining each frame
+//! onto the ones before it, so that the hash pins down every frame, where it
+//! is, and that none were left out. A `Trailer` ends the stream with that
+//! hash and the collection's parameters, signed with the agent's Ed25519
+//! key.
+//!
+//! A capture resumed from a checkpoint splices two streams together, and
+//! only the second is covered by the signature it ends with.
+
+use std::{
+    fmt, io,
+    path::PathBuf,
+    pin::pin,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_fn_stream::try_fn_stream;
+use bytes::Bytes;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use futures::{TryStream, TryStreamExt};
+use rkyv::ser::allocator::Arena;
+use serde::Serialize;
+
+use crate::{
+    encryption::{InvalidKey, hex},
+    known::from_hex,
+    stream::{Message, StreamOptions, frame},
+};
+
+/// Keeps a signature over a stream from being passed off as one over
+/// anything else.
+const CONTEXT: &[u8] = b"agentfs trailer v1\0";
+
+/// An agent's Ed25519 key, for signing what it collects.
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        Self(ed25519_dalek::SigningKey::generate(&mut OsRng))
+    }
+
+    /// The public key that checks this key's signatures, as hex.
+    pub fn signer(&self) -> String {
+        hex(self.0.verifying_key().as_bytes())
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = InvalidKey;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        from_hex(s.trim())
+            .and_then(|key| key.try_into().ok())
+            .map(|key| Self(ed25519_dalek::SigningKey::from_bytes(&key)))
+            .ok_or(InvalidKey)
+    }
+}
+
+impl fmt::Display for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey({})", self.signer())
+    }
+}
+
+/// What a collection was asked for, as recorded in its `Trailer`.
+#[derive(Debug, Serialize)]
+struct Parameters {
+    roots: Vec<String>,
+    digests: Vec<&'static str>,
+    compression: String,
+    modes: Vec<String>,
+    /// The patterns everything collected matched, if it was limited to some.
+    include: Option<Vec<String>>,
+    dedup: bool,
+    sorted: bool,
+    /// How many digests were on the known-file list, if there was one.
+    known: Option<usize>,
+    /// How many files were in the baseline, if there was one.
+    baseline: Option<usize>,
+    job: Option<String>,
+    /// The checkpoint it was resumed from, if it was.
+    resume_from: Option<String>,
+    /// Seconds since the Unix epoch.
+    started: u64,
+}
+
+/// The parameters of a collection of `roots`, as JSON.
+pub fn parameters(roots: &[PathBuf], options: &StreamOptions) -> String {
+    let parameters = Parameters {
+        roots: roots
+            .iter()
+            .map(|root| root.to_string_lossy().into_owned())
+            .collect(),
+        digests: options.digests.iter().map(|a| a.name()).collect(),
+        compression: options.compression.to_string(),
+        modes: options.modes.describe(),
+        include: options
+            .include
+            .as_ref()
+            .map(|include| include.sources().to_vec()),
+        dedup: options.dedup,
+        sorted: options.sorted || options.job.is_some(),
+        known: options.known.as_ref().map(|known| known.len()),
+        baseline: options.baseline.as_ref().map(|baseline| baseline.len()),
+        job: options.job.clone(),
+        resume_from: options.resume_from.as_ref().map(ToString::to_string),
+        started: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+    };
+
+    serde_json::to_string(&parameters).expect("parameters are always serializable")
+}
+
+/// What's signed: the hash of the stream up to the `Trailer`, and the
+/// collection's parameters.
+fn signed_message(chain: &[u8; 32], parameters: &str) -> Vec<u8> {
+    [CONTEXT, chain, parameters.as_bytes()].concat()
+}
+
+/// Whether `signature` is `signer`'s, over a stream hashing to `chain` and
+/// collected with `parameters`.
+pub fn verify(signer: &[u8; 32], chain: &[u8; 32], parameters: &str, signature: &[u8; 64]) -> bool {
+    let Ok(signer) = VerifyingKey::from_bytes(signer) else {
+        return false;
+    };
+
+    signer
+        .verify_strict(
+            &signed_message(chain, parameters),
+            &Signature::from_bytes(signature),
+        )
+        .is_ok()
+}
+
+/// Pass a framed stream through, and end it with a `Trailer` signed with
+/// `key`. A stream that fails partway isn't signed.
+pub fn sign<S>(
+    stream: S,
+    key: Arc<SigningKey>,
+    parameters: String,
+) -> impl TryStream<Ok = Bytes, Error = io::Error>
+where
+    S: TryStream<Ok = Bytes, Error = io::Error>,
+{
+    try_fn_stream(|emitter| async move {
+        let mut stream = pin!(stream.into_stream());
+        let mut chain = blake3::Hasher::new();
+
+        while let Some(bytes) = stream.try_next().await? {
+            chain.update(&bytes);
+            emitter.emit(bytes).await;
+        }
+
+        let chain = *chain.finalize().as_bytes();
+        let signature = key.0.sign(&signed_message(&chain, &parameters));
+
+        let trailer = Message::Trailer {
+            parameters,
+            chain: Box::new(chain),
+            signer: Box::new(key.0.verifying_key().to_bytes()),
+            signature: Box::new(signature.to_bytes()),
+        };
+        emitter.emit(frame(&trailer, &mut Arena::new())).await;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, stream};
+
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let key = Arc::new(SigningKey::generate());
+        assert_eq!(
+            key.signer(),
+            key.to_string().parse::<SigningKey>().unwrap().signer()
+        );
+        assert!("abcd".parse::<SigningKey>().is_err());
+
+        let frames = vec![
+            Ok(Bytes::from_static(b"AGFS")),
+            Ok(Bytes::from_static(b"...")),
+        ];
+        let signed: Vec<Bytes> =
+            block_on(sign(stream::iter(frames), key.clone(), "{}".to_owned()).try_collect())
+                .unwrap();
+        assert_eq!(3, signed.len());
+
+        let chain = *blake3::hash(b"AGFS...").as_bytes();
+        let signer = key.0.verifying_key().to_bytes();
+        let signature = key.0.sign(&signed_message(&chain, "{}")).to_bytes();
+
+        assert!(verify(&signer, &chain, "{}", &signature));
+        assert!(!verify(&signer, &chain, "{ }", &signature));
+        assert!(!verify(&signer, &[0; 32], "{}", &signature));
+        assert!(!verify(&[0; 32], &chain, "{}", &signature));
+    }
+}
@@ -0,0 +1,208 @@
+//! Reading the files being collected while disturbing them as little as
+//! possible: without updating their access times where the host allows it,
+//! and without leaving their contents behind in the page cache.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek},
+    path::Path,
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::throttle::Throttle;
+
+/// How much of a file to read at a time.
+pub const READ_SIZE: usize = 64 * 1024;
+
+/// Buffers and offsets for `O_DIRECT` reads are aligned to this, which
+/// covers the logical block size of every common device.
+const DIRECT_ALIGN: usize = 4096;
+
+/// A file opened for collection.
+pub struct SourceFile {
+    file: fs::File,
+    atime_preserved: bool,
+    direct: bool,
+    buffer: BytesMut,
+}
+
+impl SourceFile {
+    /// Open `path` with `O_NOATIME`, falling back to a plain open when the
+    /// agent doesn't own the file and lacks `CAP_FOWNER`. With `direct`, reads
+    /// bypass the page cache through `O_DIRECT` where the filesystem supports
+    /// it.
+    pub fn open(path: &Path, direct: bool) -> io::Result<Self> {
+        let mut noatime = cfg!(target_os = "linux");
+        let mut direct = direct && cfg!(target_os = "linux");
+
+        loop {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            custom_flags(&mut options, noatime, direct);
+
+            match options.open(path) {
+                Ok(file) => {
+                    return Ok(Self {
+                        file,
+                        atime_preserved: noatime,
+                        direct,
+                        buffer: BytesMut::new(),
+                    });
+                }
+                // EPERM when O_NOATIME is asked for on someone else's file.
+                Err(e) if noatime && e.kind() == io::ErrorKind::PermissionDenied => {
+                    noatime = false;
+                }
+                // EINVAL when the filesystem doesn't do O_DIRECT.
+                Err(e) if direct && e.kind() == io::ErrorKind::InvalidInput => {
+                    direct = false;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Go back to the start, to read the file again.
+    pub fn rewind(&mut self) -> io::Result<()> {
+        self.file.rewind()
+    }
+
+    /// Whether reading this file leaves its access time alone.
+    pub fn atime_preserved(&self) -> bool {
+        self.atime_preserved
+    }
+
+    /// Read the next chunk of the file, which is empty at the end. The chunk
+    /// is handed over rather than copied, so the next one goes into a fresh
+    /// buffer.
+    pub fn read_chunk(&mut self, throttle: &Throttle) -> io::Result<Bytes> {
+        if self.direct {
+            // Leave room to start the read on an aligned address.
+            self.buffer.clear();
+            self.buffer.reserve(READ_SIZE + DIRECT_ALIGN);
+            let offset = self.buffer.as_ptr().align_offset(DIRECT_ALIGN);
+            self.buffer.resize(offset + READ_SIZE, 0);
+            self.buffer.advance(offset);
+        } else {
+            self.buffer.resize(READ_SIZE, 0);
+        }
+
+        let read = loop {
+            throttle.read_ops.acquire_blocking(1);
+
+            match self.file.read(&mut self.buffer) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // A short read leaves the offset unaligned for the next one,
+                // so carry on through the page cache.
+                Err(e) if self.direct && e.kind() == io::ErrorKind::InvalidInput => {
+                    clear_direct(&self.file)?;
+                    self.direct = false;
+                }
+                result => break result?,
+            }
+        };
+
+        throttle.read_bytes.acquire_blocking(read as u64);
+        self.buffer.truncate(read);
+
+        Ok(self.buffer.split().freeze())
+    }
+}
+
+impl Drop for SourceFile {
+    /// Let go of whatever the reads pulled into the page cache.
+    fn drop(&mut self) {
+        drop_cached_pages(&self.file);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn custom_flags(options: &mut OpenOptions, noatime: bool, direct: bool) {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut flags = 0;
+
+    if noatime {
+        flags |= libc::O_NOATIME;
+    }
+
+    if direct {
+        flags |= libc::O_DIRECT;
+    }
+
+    options.custom_flags(flags);
+}
+
+#[cfg(not(target_os = "linux"))]
+fn custom_flags(_options: &mut OpenOptions, _noatime: bool, _direct: bool) {}
+
+#[cfg(target_os = "linux")]
+fn clear_direct(file: &fs::File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clear_direct(_file: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn drop_cached_pages(file: &fs::File) {
+    use std::os::fd::AsRawFd;
+
+    // Only advice, so there's nothing to do if the kernel ignores it.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cached_pages(_file: &fs::File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mut file: SourceFile) -> Vec<u8> {
+        let throttle = Throttle::default();
+        let mut contents = Vec::new();
+
+        loop {
+            let chunk = file.read_chunk(&throttle).unwrap();
+
+            if chunk.is_empty() {
+                return contents;
+            }
+
+            contents.extend_from_slice(&chunk);
+        }
+    }
+
+    #[test]
+    fn test_source_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let data: Vec<u8> = (0..3 * READ_SIZE + 1234).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        // The test owns the file, so O_NOATIME is always allowed.
+        let file = SourceFile::open(&path, false).unwrap();
+        assert_eq!(cfg!(target_os = "linux"), file.atime_preserved());
+        assert_eq!(data, read_all(file));
+
+        // Whether or not the filesystem takes O_DIRECT, the contents are the
+        // same.
+        let file = SourceFile::open(&path, true).unwrap();
+        assert_eq!(data, read_all(file));
+
+        assert!(SourceFile::open(&dir.path().join("missing"), true).is_err());
+    }
+}
@@ -9,153 +9,1138 @@
 // https://gitlab.com/asuran-rs/hole-punch
 
 use std::{
-    io::{self, Write},
-    path::PathBuf,
-    pin::pin,
+    collections::VecDeque,
+    fmt,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
 };
 
-use async_fn_stream::try_fn_stream;
-use bytes::{Bytes, BytesMut};
-use filesystem_iter::{file_offline::FileOffline, root_iterator, root_iterator_package};
-use futures::TryStream;
+use async_fn_stream::{TryStreamEmitter, try_fn_stream};
+use bytes::{BufMut, Bytes, BytesMut};
+use fastcdc::v2020::StreamCDC;
+use filesystem_iter::{
+    file_offline::FileOffline, recursive_pattern, root_iterator_package, stream::root_stream,
+};
+use futures::{StreamExt, TryStream};
 use log::debug;
-use lz4_flex::frame::{BlockMode, FrameEncoder, FrameInfo};
-use md5::Md5;
 use rkyv::{
-    Archive, Deserialize, Serialize, api::high::to_bytes_with_alloc, rancor, ser::allocator::Arena,
+    Archive, Deserialize, Serialize,
+    api::high::to_bytes_in_with_alloc,
+    rancor,
+    ser::{Positional, Writer, allocator::Arena},
 };
-use sha3::{Digest, Sha3_256};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, BufReader},
+use tokio::sync::mpsc;
+
+use crate::{
+    baseline::{Baseline, BaselineFile},
+    chunking::{AVG_CHUNK, Blocks, ChunkHash, MAX_CHUNK, MIN_CHUNK, SentChunks},
+    codec::{Codec, Compression, Encoder},
+    digest::{Digest, DigestAlgorithm, Hashers},
+    encryption::{Recipient, hex},
+    known::{KnownHashes, from_hex},
+    mode::{CollectionMode, ModeRules, PatternSet},
+    signing::SigningKey,
+    source::{READ_SIZE, SourceFile},
+    throttle::{Throttle, spawn_reader},
 };
 
+/// Marks the start of every frame, so a reader can find its way back into a
+/// damaged stream.
+pub const FRAME_MAGIC: [u8; 4] = *b"AGFS";
+
+/// Magic, then the payload's little-endian length and CRC-32.
+pub const FRAME_HEADER_LEN: usize = FRAME_MAGIC.len() + 2 * size_of::<u32>();
+
+/// No frame is ever this large, so a bigger length means the header is junk.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Files no bigger than this are kept in memory while they're checked
+/// against the known-file list, rather than read twice.
+const PREHASH_BUFFER: usize = 1024 * 1024;
+
+/// Directories and small files waiting behind a large one are held back at
+/// most this many entries deep.
+const MAX_PENDING: usize = 1024;
+
+/// A resumable stream sends a checkpoint after at least this many bytes or
+/// entries, whichever comes first.
+const CHECKPOINT_BYTES: u64 = 1024 * 1024;
+const CHECKPOINT_ENTRIES: u64 = 1000;
+
+/// Bumped whenever the meaning of existing messages changes.
+pub const STREAM_VERSION: u32 = 1;
+
 #[derive(Debug, Archive, Serialize, Deserialize)]
 pub enum Message {
-    FileHeader { path: String, len: u64 },
-    FileBody { data: Bytes },
-    FileFooter { sha256: [u8; 32], md5: [u8; 16] },
-    Directory { path: String },
+    FileHeader {
+        path: Vec<u8>,
+        len: u64,
+        /// How the contents in the following `FileBody` messages are
+        /// compressed. Contents sent as `Chunk`s are compressed a chunk at a
+        /// time instead, and their header says `Store`.
+        codec: Codec,
+        metadata: EntryMetadata,
+        /// Whether the agent read the file without updating its access
+        /// time.
+        atime_preserved: bool,
+        /// `Metadata` and `Unchanged` files are followed by nothing, and
+        /// `Hash` files by just their `FileFooter`. `Known` files are
+        /// followed by a `FileFooter` holding only the digest that matched.
+        mode: CollectionMode,
+    },
+    FileBody {
+        data: Bytes,
+    },
+    FileFooter {
+        digests: Vec<Digest>,
+    },
+    Directory {
+        path: Vec<u8>,
+        metadata: EntryMetadata,
+    },
+    /// Always the first message, describing how the rest of the stream was
+    /// put together.
+    StreamHeader {
+        version: u32,
+        /// The digests every `FileFooter` carries, in order.
+        digests: Vec<DigestAlgorithm>,
+        /// The collection this stream belongs to, which it can be resumed
+        /// as. Empty if it can't be.
+        job: String,
+    },
+    /// A piece of the current file's contents, in place of `FileBody`
+    /// messages when the stream is deduplicated. Kept by the decoder in case
+    /// a later `ChunkRef` needs it.
+    Chunk {
+        hash: ChunkHash,
+        codec: Codec,
+        data: Bytes,
+    },
+    /// A piece of the current file's contents that went out earlier in the
+    /// stream as a `Chunk`.
+    ChunkRef {
+        hash: ChunkHash,
+    },
+    /// A file in the baseline that the walk no longer found, sent once the
+    /// walk is done.
+    Deleted {
+        path: Vec<u8>,
+    },
+    /// Sent between entries every so often in a resumable stream, once
+    /// everything up to and including the entry at `path` has been sent.
+    /// The walk is sorted, so that's a place in it a resumed stream can
+    /// pick up from.
+    Checkpoint {
+        path: Vec<u8>,
+        /// Whether the entry was a `Deleted` record, which come after the
+        /// walk.
+        deleted: bool,
+    },
+    /// The last message of a signed stream. See `signing`.
+    ///
+    /// The keys and hashes are boxed, since every message is as large as the
+    /// largest variant.
+    Trailer {
+        /// What the collection was asked for, as JSON.
+        parameters: String,
+        /// The BLAKE3 hash of every byte of the stream before this frame.
+        chain: Box<[u8; 32]>,
+        /// The agent's Ed25519 public key.
+        signer: Box<[u8; 32]>,
+        /// Over `chain` and `parameters`.
+        signature: Box<[u8; 64]>,
+    },
+}
+
+/// Per-collection choices about what goes into the stream.
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    pub digests: Vec<DigestAlgorithm>,
+    pub compression: Compression,
+    /// How many files are read, hashed and compressed at once. Their frames
+    /// are still sent one file at a time, in walk order.
+    pub concurrency: usize,
+    /// Roughly how many bytes of frames may be waiting to be sent, across
+    /// every file in flight.
+    pub memory_budget: usize,
+    /// Shared with every other collection, and adjustable while running.
+    pub throttle: Arc<Throttle>,
+    /// Read files at idle I/O priority, on Linux.
+    pub idle_io_priority: bool,
+    /// Read, hash and compress files at the lowest CPU priority, on Linux.
+    pub low_cpu_priority: bool,
+    /// Read files with `O_DIRECT` rather than dropping them from the page
+    /// cache afterwards, on Linux.
+    pub direct_io: bool,
+    /// Which files are sent in full, and which only as metadata or digests.
+    pub modes: ModeRules,
+    /// Leave out every entry that doesn't match, as though the walk never
+    /// came across it.
+    pub include: Option<Arc<PatternSet>>,
+    /// Files whose digest is on this list are sent as `Known` instead.
+    pub known: Option<Arc<KnownHashes>>,
+    /// Split files into content-defined chunks, and send each distinct chunk
+    /// once.
+    pub dedup: bool,
+    /// Walk each directory's entries sorted by name, so that captures of the
+    /// same tree are sent in the same order.
+    pub sorted: bool,
+    /// Files that haven't changed since this baseline are sent as
+    /// `Unchanged`, and those it has that are gone as `Deleted`.
+    pub baseline: Option<Arc<Baseline>>,
+    /// Encrypt the stream to these, unless there are none.
+    pub recipients: Vec<Recipient>,
+    /// End the stream with a `Trailer` signed with this key.
+    pub signing_key: Option<Arc<SigningKey>>,
+    /// Makes the stream resumable. The walk is sorted, as though `sorted`
+    /// were set, so that it's the same each time, and checkpoints are sent
+    /// along the way.
+    pub job: Option<String>,
+    /// Pick up just after this entry, the last one an interrupted attempt
+    /// at the same job sent.
+    pub resume_from: Option<ResumePoint>,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            digests: DigestAlgorithm::DEFAULT.to_vec(),
+            compression: Compression::default(),
+            concurrency: thread::available_parallelism().map_or(4, |n| n.get()),
+            memory_budget: 64 * 1024 * 1024,
+            throttle: Arc::default(),
+            idle_io_priority: false,
+            low_cpu_priority: false,
+            direct_io: false,
+            modes: ModeRules::default(),
+            include: None,
+            known: None,
+            dedup: false,
+            sorted: false,
+            baseline: None,
+            recipients: Vec::new(),
+            signing_key: None,
+            job: None,
+            resume_from: None,
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Whether `path` isn't left out by `include`.
+    pub fn is_included(&self, path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(path))
+    }
+}
+
+/// The last entry an interrupted attempt at a job sent, as a checkpoint
+/// named it. Written as its path in hex, prefixed with `deleted:` if it was
+/// a `Deleted` record.
+///
+/// This names the entry rather than counting entries: a sequence number
+/// only means the same thing if the walk up to it turns out exactly the
+/// same again, and any file created or removed in the meantime would shift
+/// everything after it. A path is found again by where it sorts in the
+/// walk, whether or not it still exists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumePoint {
+    pub path: PathBuf,
+    pub deleted: bool,
+}
+
+impl fmt::Display for ResumePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.deleted {
+            f.write_str("deleted:")?;
+        }
+
+        f.write_str(&hex(&path_to_bytes(&self.path)))
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidResumePoint(String);
+
+impl fmt::Display for InvalidResumePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid checkpoint `{}`", self.0)
+    }
+}
+
+impl std::error::Error for InvalidResumePoint {}
+
+impl FromStr for ResumePoint {
+    type Err = InvalidResumePoint;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, deleted) = match s.strip_prefix("deleted:") {
+            Some(path) => (path, true),
+            None => (s, false),
+        };
+
+        match from_hex(path) {
+            Some(path) if !path.is_empty() => Ok(Self {
+                path: path_from_bytes(&path),
+                deleted,
+            }),
+            _ => Err(InvalidResumePoint(s.to_owned())),
+        }
+    }
+}
+
+/// Where `path` comes in a sorted walk of `roots`: each root in turn, and
+/// within one, depth first with every directory's entries by name. That's
+/// the order `Path` compares in, component by component.
+fn walk_position<'a>(roots: &[PathBuf], path: &'a Path) -> (usize, &'a Path) {
+    let root = roots.iter().position(|root| path.starts_with(root));
+
+    (root.unwrap_or(roots.len()), path)
+}
+
+/// The parts of a filesystem entry's metadata worth preserving.
+#[derive(Debug, Default, Clone, Archive, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    /// Permission bits, as in `st_mode & 0o7777`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time, in seconds since the Unix epoch.
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    /// Zero where there are no inodes.
+    pub inode: u64,
+}
+
+impl From<&Metadata> for EntryMetadata {
+    #[cfg(unix)]
+    fn from(meta: &Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec() as u32,
+            inode: meta.ino(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from(meta: &Metadata) -> Self {
+        use std::time::UNIX_EPOCH;
+
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let mode = match (meta.is_dir(), meta.permissions().readonly()) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644,
+        };
+
+        Self {
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: mtime.as_secs() as i64,
+            mtime_nsec: mtime.subsec_nanos(),
+            inode: 0,
+        }
+    }
+}
+
+impl From<&ArchivedEntryMetadata> for EntryMetadata {
+    fn from(archived: &ArchivedEntryMetadata) -> Self {
+        Self {
+            mode: archived.mode.to_native(),
+            uid: archived.uid.to_native(),
+            gid: archived.gid.to_native(),
+            mtime: archived.mtime.to_native(),
+            mtime_nsec: archived.mtime_nsec.to_native(),
+            inode: archived.inode.to_native(),
+        }
+    }
+}
+
+/// The raw bytes of a path, so that names which aren't valid UTF-8 survive
+/// the trip across the wire.
+///
+/// On Windows this is WTF-8, which is plain UTF-8 for any well-formed name.
+pub fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_encoded_bytes().to_vec()
+}
+
+/// Rebuild a path sent by `path_to_bytes`.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    OsStr::from_bytes(bytes).into()
+}
+
+/// Rebuild a path sent by `path_to_bytes`.
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
 }
 
 pub fn build_stream(
-    roots: impl Iterator<Item = PathBuf>,
+    roots: impl Iterator<Item = PathBuf> + Clone,
+    options: StreamOptions,
 ) -> impl TryStream<Ok = Bytes, Error = io::Error> {
     try_fn_stream(|emitter| async move {
-        let mut bytes = BytesMut::with_capacity(1024 * 64);
-        let mut enc_buffer = Vec::new();
         let mut arena = Arena::new();
+        let options = Arc::new(options);
+        let concurrency = options.concurrency.max(1);
+
+        // Every file in flight gets an equal share of the budget, so the one
+        // being sent can always make progress however far the others have
+        // run ahead.
+        let capacity = (options.memory_budget / concurrency / READ_SIZE).max(1);
+
+        let stream_header = Message::StreamHeader {
+            version: STREAM_VERSION,
+            digests: options.digests.clone(),
+            job: options.job.clone().unwrap_or_default(),
+        };
+        emit(&emitter, &stream_header, &mut arena).await;
+
+        let root_paths: Vec<_> = roots.clone().collect();
+        let patterns = roots.clone().map(recursive_pattern);
+        let package = root_iterator_package(roots, patterns).unwrap();
+        let mut entries = root_stream(package.sorted(options.sorted || options.job.is_some()));
 
-        let package = root_iterator_package(roots, &["**"]).unwrap();
-        let iter = pin!(root_iterator(package));
+        // Entries in walk order, waiting to be sent.
+        let mut pending = VecDeque::new();
+        let mut files_in_flight = 0;
+        let sent_chunks = Arc::new(SentChunks::default());
+        let mut files = 0;
+        // Which of the baseline's files the walk has come across.
+        let mut seen = vec![false; options.baseline.as_ref().map_or(0, |b| b.len())];
+        // Paths the walk couldn't read, whose baseline files may well still
+        // be there.
+        let mut unreadable = Vec::new();
+        // Where an earlier attempt got to, until the walk is past it.
+        let mut resume_from = options.resume_from.as_ref();
+        let mut checkpoints = Checkpoints::new(&options);
 
         debug!("About to start iterating");
 
-        for entry in iter {
+        while let Some(entry) = entries.next().await {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    debug!("Couldn't walk {err}");
+
+                    // Something that's gone really is deleted.
+                    if err.io_error().kind() != io::ErrorKind::NotFound
+                        && let Some(path) = err.path()
+                    {
+                        unreadable.push(path.to_owned());
+                    }
+
+                    continue;
+                }
+            };
+
             let path = entry.path();
+            let meta = entry.metadata();
 
             debug!("--- {}", path.display());
 
-            let Ok(meta) = path.metadata() else {
-                // We probably cannot even access the file at this point, abort!
-                continue;
+            let baseline = match &options.baseline {
+                Some(baseline) if meta.is_file() => baseline.get(path),
+                _ => None,
             };
 
+            if let Some((index, _)) = baseline {
+                seen[index] = true;
+            }
+
+            if !options.is_included(path) {
+                continue;
+            }
+
             if meta.is_offline() {
                 // Skip opening/reading cloud-hosted content
                 continue;
             }
 
-            let header = if path.is_file() {
-                Message::FileHeader {
-                    path: path.to_string_lossy().into_owned(),
+            // What an earlier attempt sent is walked again, but not resent.
+            if let Some(from) = resume_from {
+                if from.deleted
+                    || walk_position(&root_paths, path) <= walk_position(&root_paths, &from.path)
+                {
+                    continue;
+                }
+
+                resume_from = None;
+            }
+
+            let mut mode = options.modes.mode_for(path);
+
+            if baseline.is_some_and(|(_, file)| file.is_unchanged(meta)) {
+                mode = CollectionMode::Unchanged;
+            }
+
+            if meta.is_file() && !mode.has_footer() {
+                // Nothing to read, so nothing to wait for.
+                let header = Message::FileHeader {
+                    path: path_to_bytes(path),
                     len: meta.len(),
+                    codec: Codec::Store,
+                    metadata: EntryMetadata::from(meta),
+                    atime_preserved: true,
+                    mode,
+                };
+
+                pending.push_back(Pending {
+                    path: path_to_bytes(path),
+                    frames: Frames::Ready(frame_parts(&header, &mut arena)),
+                });
+            } else if meta.is_file() {
+                let path = path_to_bytes(path);
+                let (sender, receiver) = mpsc::channel(capacity);
+                let file = FileJob {
+                    index: files,
+                    mode,
+                    options: options.clone(),
+                    sent_chunks: sent_chunks.clone(),
+                    baseline: baseline.and_then(|(_, file)| baseline_digest(file, meta, &options)),
+                };
+
+                let errors = sender.clone();
+                let reader = spawn_reader(
+                    options.idle_io_priority,
+                    options.low_cpu_priority,
+                    move || encode_file(entry.path(), entry.metadata(), &file, &sender),
+                );
+
+                match reader {
+                    // What it reads comes through `receiver`, so there's nothing to wait on.
+                    Ok(reader) => drop(reader),
+                    // As though it failed to read. Nothing has been sent yet, so
+                    // there's room.
+                    Err(error) => {
+                        let _ = errors.try_send(Err(error));
+                    }
                 }
+
+                pending.push_back(Pending {
+                    path,
+                    frames: Frames::File(receiver),
+                });
+                files_in_flight += 1;
+                files += 1;
             } else {
-                Message::Directory {
-                    path: path.to_string_lossy().into_owned(),
-                }
-            };
+                let directory = Message::Directory {
+                    path: path_to_bytes(path),
+                    metadata: EntryMetadata::from(meta),
+                };
 
-            emitter
-                .emit(Bytes::copy_from_slice(
-                    to_bytes_with_alloc::<_, rancor::Error>(&header, arena.acquire())
-                        .unwrap()
-                        .as_slice(),
-                ))
-                .await;
+                pending.push_back(Pending {
+                    path: path_to_bytes(path),
+                    frames: Frames::Ready(frame_parts(&directory, &mut arena)),
+                });
+            }
 
-            // Nothing left to do if it's a directory.
-            if path.is_dir() {
-                continue;
+            while files_in_flight >= concurrency || pending.len() > MAX_PENDING {
+                let Some(next) = pending.pop_front() else {
+                    break;
+                };
+
+                if let Frames::File(_) = next.frames {
+                    files_in_flight -= 1;
+                }
+
+                send(&emitter, next, &options.throttle, &mut checkpoints).await?;
             }
+        }
 
-            // Build up LZ4 compression for file contents
-            let frame_info = FrameInfo::new().block_mode(BlockMode::Linked);
-
-            // Ensure buffer is clear
-            enc_buffer.clear();
-
-            let mut encoder = FrameEncoder::with_frame_info(frame_info, &mut enc_buffer);
-            let mut md5 = Md5::new();
-            let mut sha256 = Sha3_256::new();
-
-            let file = File::open(path).await?;
-            let mut reader = BufReader::new(file);
-
-            while reader.read_buf(&mut bytes).await? > 0 {
-                encoder.write_all(&bytes).unwrap();
-                md5.update(&bytes);
-                sha256.update(&bytes);
-
-                // debug!("Handled {} bytes", bytes.len());
-
-                bytes.clear();
-
-                // Get data from the encoder, and yield those bytes.
-                let buffer = encoder.get_mut();
-                let emit_bytes = Bytes::copy_from_slice(buffer.as_slice());
-                let body = Message::FileBody { data: emit_bytes };
-                emitter
-                    .emit(Bytes::copy_from_slice(
-                        to_bytes_with_alloc::<_, rancor::Error>(&body, arena.acquire())
-                            .unwrap()
-                            .as_slice(),
-                    ))
-                    .await;
-                buffer.clear();
-            }
-
-            // Finalize the encoder, get its remaining buffer data, and yield those bytes.
-            let buffer = encoder.finish().unwrap();
-            let emit_bytes = Bytes::copy_from_slice(buffer.as_slice());
-            let body = Message::FileBody { data: emit_bytes };
-            emitter
-                .emit(Bytes::copy_from_slice(
-                    to_bytes_with_alloc::<_, rancor::Error>(&body, arena.acquire())
-                        .unwrap()
-                        .as_slice(),
-                ))
-                .await;
-
-            // Temp file contents separator for debugging
-            let md5_final = md5.finalize();
-            let sha256_final = sha256.finalize();
-
-            let footer = Message::FileFooter {
-                md5: md5_final.into(),
-                sha256: sha256_final.into(),
-            };
+        while let Some(next) = pending.pop_front() {
+            send(&emitter, next, &options.throttle, &mut checkpoints).await?;
+        }
+
+        if let Some(baseline) = &options.baseline {
+            let mut deleted: Vec<_> = baseline
+                .files()
+                .iter()
+                .zip(seen)
+                .map(|(file, seen)| (file.path.as_path(), seen))
+                .filter(|&(path, seen)| {
+                    !seen
+                        && options.is_included(path)
+                        && root_paths.iter().any(|root| path.starts_with(root))
+                        && !unreadable.iter().any(|dir| path.starts_with(dir))
+                })
+                .map(|(path, _)| path)
+                .collect();
+
+            // In walk order too, so that there's somewhere to resume from.
+            deleted.sort_by_key(|path| walk_position(&root_paths, path));
+            checkpoints.deleted = true;
+
+            let resume_from = options.resume_from.as_ref().filter(|from| from.deleted);
 
-            emitter
-                .emit(Bytes::copy_from_slice(
-                    to_bytes_with_alloc::<_, rancor::Error>(&footer, arena.acquire())
-                        .unwrap()
-                        .as_slice(),
-                ))
-                .await;
+            for path in deleted {
+                if resume_from.is_some_and(|from| {
+                    walk_position(&root_paths, path) <= walk_position(&root_paths, &from.path)
+                }) {
+                    continue;
+                }
+
+                let deleted = Message::Deleted {
+                    path: path_to_bytes(path),
+                };
+                let parts = frame_parts(&deleted, &mut arena);
+
+                send(
+                    &emitter,
+                    Pending {
+                        path: path_to_bytes(path),
+                        frames: Frames::Ready(parts),
+                    },
+                    &options.throttle,
+                    &mut checkpoints,
+                )
+                .await?;
+            }
+        }
+
+        if let Some(checkpoint) = checkpoints.finish() {
+            emit(&emitter, &checkpoint, &mut arena).await;
         }
 
         Ok(())
     })
 }
+
+/// An entry that has been walked but not yet sent.
+struct Pending {
+    /// For the checkpoint after it.
+    path: Vec<u8>,
+    frames: Frames,
+}
+
+enum Frames {
+    Ready(Vec<Bytes>),
+    /// The frames of a file, as its encoder produces them.
+    File(mpsc::Receiver<io::Result<Bytes>>),
+}
+
+/// Send everything for one entry, waiting on its encoder if need be, and a
+/// checkpoint after it if one is due.
+async fn send(
+    emitter: &TryStreamEmitter<Bytes, io::Error>,
+    pending: Pending,
+    throttle: &Throttle,
+    checkpoints: &mut Checkpoints,
+) -> io::Result<()> {
+    let mut sent = 0;
+
+    match pending.frames {
+        Frames::Ready(parts) => {
+            for part in parts {
+                sent += part.len() as u64;
+                throttle.send.acquire(part.len() as u64).await;
+                emitter.emit(part).await;
+            }
+        }
+        Frames::File(mut receiver) => {
+            while let Some(part) = receiver.recv().await {
+                let part = part?;
+                sent += part.len() as u64;
+                throttle.send.acquire(part.len() as u64).await;
+                emitter.emit(part).await;
+            }
+        }
+    }
+
+    if let Some(checkpoint) = checkpoints.sent(pending.path, sent) {
+        emit(emitter, &checkpoint, &mut Arena::new()).await;
+    }
+
+    Ok(())
+}
+
+/// Keeps track of how much of a resumable stream has been sent.
+struct Checkpoints {
+    resumable: bool,
+    /// Whether entries are `Deleted` records now, the walk being done.
+    deleted: bool,
+    /// The path of the last entry sent in full.
+    last: Vec<u8>,
+    /// Sent since the last checkpoint.
+    bytes: u64,
+    entries: u64,
+}
+
+impl Checkpoints {
+    fn new(options: &StreamOptions) -> Self {
+        Self {
+            resumable: options.job.is_some(),
+            deleted: false,
+            last: Vec::new(),
+            bytes: 0,
+            entries: 0,
+        }
+    }
+
+    /// Count the entry at `path`, of `bytes`, as sent, returning a
+    /// checkpoint if one is due.
+    fn sent(&mut self, path: Vec<u8>, bytes: u64) -> Option<Message> {
+        self.last = path;
+        self.bytes += bytes;
+        self.entries += 1;
+
+        if self.bytes >= CHECKPOINT_BYTES || self.entries >= CHECKPOINT_ENTRIES {
+            self.checkpoint()
+        } else {
+            None
+        }
+    }
+
+    /// The checkpoint for the end of the stream, unless it was just sent.
+    fn finish(&mut self) -> Option<Message> {
+        (self.entries > 0).then(|| self.checkpoint()).flatten()
+    }
+
+    fn checkpoint(&mut self) -> Option<Message> {
+        self.bytes = 0;
+        self.entries = 0;
+
+        self.resumable.then(|| Message::Checkpoint {
+            path: self.last.clone(),
+            deleted: self.deleted,
+        })
+    }
+}
+
+/// What `encode_file` needs to know beyond the file itself.
+struct FileJob {
+    /// The file's position in walk order, counting files only.
+    index: u64,
+    mode: CollectionMode,
+    options: Arc<StreamOptions>,
+    sent_chunks: Arc<SentChunks>,
+    /// What the file's contents were in the baseline, if they might still
+    /// be.
+    baseline: Option<Digest>,
+}
+
+/// The digest to hash a file for, if its metadata changed since the baseline
+/// but its size didn't.
+fn baseline_digest(
+    file: &BaselineFile,
+    meta: &Metadata,
+    options: &StreamOptions,
+) -> Option<Digest> {
+    (file.size == meta.len())
+        .then(|| file.digest(&options.digests).cloned())
+        .flatten()
+}
+
+/// Read, hash and compress a file on a blocking thread, passing its frames
+/// to `sender`. Stops early if the stream has gone away. In `Hash` mode the
+/// contents are only hashed.
+///
+/// With a list of known files, the file is hashed before anything is sent,
+/// and a match is sent as `Known` rather than in full. The same goes for a
+/// file that may be as it was in the baseline, sent as `Unchanged` if so.
+/// Otherwise it's read again to be sent, unless it was small enough to keep
+/// from the first time.
+fn encode_file(
+    path: &Path,
+    meta: &Metadata,
+    job: &FileJob,
+    sender: &mpsc::Sender<io::Result<Bytes>>,
+) {
+    let (mode, options) = (job.mode, &*job.options);
+
+    let mut arena = Arena::new();
+    let mut send = |message: &Message| {
+        frame_parts(message, &mut arena)
+            .into_iter()
+            .try_for_each(|part| sender.blocking_send(Ok(part)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    };
+
+    let result = (|| {
+        let mut file = SourceFile::open(path, options.direct_io)?;
+        let header = |codec, mode, atime_preserved| Message::FileHeader {
+            path: path_to_bytes(path),
+            len: meta.len(),
+            codec,
+            metadata: EntryMetadata::from(meta),
+            atime_preserved,
+            mode,
+        };
+
+        // What the known-file check already read, if it was the whole file.
+        let mut replay = None;
+
+        if options.known.is_some() || job.baseline.is_some() {
+            let mut algorithms = options.digests.clone();
+            let extra = options.known.iter().map(|known| known.algorithm());
+
+            for algorithm in extra.chain(job.baseline.iter().map(|digest| digest.algorithm)) {
+                if !algorithms.contains(&algorithm) {
+                    algorithms.push(algorithm);
+                }
+            }
+
+            let mut hashers = Hashers::new(&algorithms);
+            let mut chunks = Some(Vec::new());
+            let mut read = 0;
+
+            loop {
+                let chunk = file.read_chunk(&options.throttle)?;
+
+                if chunk.is_empty() {
+                    break;
+                }
+
+                hashers.update(&chunk);
+                read += chunk.len();
+
+                match &mut chunks {
+                    Some(chunks) if read <= PREHASH_BUFFER => chunks.push(chunk),
+                    _ => chunks = None,
+                }
+            }
+
+            let mut digests = hashers.finalize();
+            let known = options
+                .known
+                .as_ref()
+                .and_then(|known| digests.iter().find(|digest| known.contains(digest)));
+
+            if let Some(digest) = known {
+                send(&header(
+                    Codec::Store,
+                    CollectionMode::Known,
+                    file.atime_preserved(),
+                ))?;
+
+                return send(&Message::FileFooter {
+                    digests: vec![digest.clone()],
+                });
+            }
+
+            if job
+                .baseline
+                .as_ref()
+                .is_some_and(|digest| digests.contains(digest))
+            {
+                return send(&header(
+                    Codec::Store,
+                    CollectionMode::Unchanged,
+                    file.atime_preserved(),
+                ));
+            }
+
+            // Everything a `Hash` file needs is already here.
+            if mode == CollectionMode::Hash {
+                digests.truncate(options.digests.len());
+
+                send(&header(Codec::Store, mode, file.atime_preserved()))?;
+
+                return send(&Message::FileFooter { digests });
+            }
+
+            match chunks {
+                Some(chunks) => replay = Some(chunks.into_iter()),
+                None => file.rewind()?,
+            }
+        }
+
+        let mut next_chunk = |file: &mut SourceFile| match &mut replay {
+            Some(chunks) => Ok(chunks.next().unwrap_or_default()),
+            None => file.read_chunk(&options.throttle),
+        };
+
+        let full = mode == CollectionMode::Full;
+        let mut hashers = Hashers::new(&options.digests);
+
+        // The first block decides whether the contents are worth
+        // compressing.
+        let mut chunk = next_chunk(&mut file)?;
+
+        if full && options.dedup {
+            send(&header(Codec::Store, mode, file.atime_preserved()))?;
+
+            let blocks = Blocks::new(chunk, || next_chunk(&mut file));
+
+            for piece in StreamCDC::new(blocks, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+                let data = Bytes::from(piece?.data);
+                let hash = *blake3::hash(&data).as_bytes();
+                hashers.update(&data);
+
+                if job.sent_chunks.claim(hash, job.index) {
+                    send(&Message::ChunkRef { hash })?;
+                } else {
+                    let codec = options.compression.codec_for(&data);
+                    let mut encoder = Encoder::new(codec, options.compression)?;
+                    let (data, rest) = (encoder.encode(data)?, encoder.finish()?);
+                    let data = match rest.is_empty() {
+                        true => data,
+                        false => [data, rest].concat().into(),
+                    };
+
+                    send(&Message::Chunk { hash, codec, data })?;
+                }
+            }
+
+            return send(&Message::FileFooter {
+                digests: hashers.finalize(),
+            });
+        }
+
+        let codec = match mode {
+            CollectionMode::Full => options.compression.codec_for(&chunk),
+            _ => Codec::Store,
+        };
+
+        send(&header(codec, mode, file.atime_preserved()))?;
+
+        let mut encoder = Encoder::new(codec, options.compression)?;
+
+        while !chunk.is_empty() {
+            hashers.update(&chunk);
+
+            if full {
+                let data = encoder.encode(chunk)?;
+                send(&Message::FileBody { data })?;
+            }
+
+            chunk = next_chunk(&mut file)?;
+        }
+
+        // Finalize the encoder, and send its remaining data.
+        if full {
+            send(&Message::FileBody {
+                data: encoder.finish()?,
+            })?;
+        }
+
+        send(&Message::FileFooter {
+            digests: hashers.finalize(),
+        })
+    })();
+
+    if let Err(error) = result {
+        // Nobody is listening any more if this fails too.
+        let _ = sender.blocking_send(Err(error));
+    }
+}
+
+/// Send a message's frame down the stream.
+async fn emit(emitter: &TryStreamEmitter<Bytes, io::Error>, message: &Message, arena: &mut Arena) {
+    for part in frame_parts(message, arena) {
+        emitter.emit(part).await;
+    }
+}
+
+/// Serialize a message and put a frame header in front of it, so a reader can
+/// split the stream back into individual messages and detect damage.
+pub fn frame(message: &Message, arena: &mut Arena) -> Bytes {
+    match frame_parts(message, arena).as_slice() {
+        [frame] => frame.clone(),
+        parts => parts.concat().into(),
+    }
+}
+
+/// The same frame as `frame`, split into parts that are sent one after the
+/// other. A `FileBody` or `Chunk`'s data is one of the parts, shared rather than copied.
+pub fn frame_parts(message: &Message, arena: &mut Arena) -> Vec<Bytes> {
+    let body = match message {
+        Message::FileBody { data } | Message::Chunk { data, .. } => Some(data.clone()),
+        _ => None,
+    };
+
+    to_bytes_in_with_alloc::<_, _, rancor::Error>(message, FrameWriter::new(body), arena.acquire())
+        .unwrap()
+        .finish()
+}
+
+/// Serializes a message straight into its frame, splicing in the body data
+/// when rkyv writes it out instead of copying it.
+///
+/// rkyv writes out a `Bytes` field's contents with a single call, so that
+/// call is spotted by the slice it's given being the body itself.
+struct FrameWriter {
+    /// The frame header, and everything written before the body data.
+    head: BytesMut,
+    body: Option<Bytes>,
+    spliced: Option<Bytes>,
+    /// Everything written after the body data.
+    tail: BytesMut,
+    len: usize,
+    crc: crc32fast::Hasher,
+}
+
+impl FrameWriter {
+    fn new(body: Option<Bytes>) -> Self {
+        let mut head = BytesMut::with_capacity(256);
+        head.resize(FRAME_HEADER_LEN, 0);
+
+        Self {
+            head,
+            body: body.filter(|body| !body.is_empty()),
+            spliced: None,
+            tail: BytesMut::new(),
+            len: 0,
+            crc: crc32fast::Hasher::new(),
+        }
+    }
+
+    fn finish(mut self) -> Vec<Bytes> {
+        let mut header = &mut self.head[..FRAME_HEADER_LEN];
+        header.put_slice(&FRAME_MAGIC);
+        header.put_u32_le(self.len as u32);
+        header.put_u32_le(self.crc.finalize());
+
+        [
+            Some(self.head.freeze()),
+            self.spliced,
+            Some(self.tail.freeze()),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|part| !part.is_empty())
+        .collect()
+    }
+}
+
+impl Positional for FrameWriter {
+    fn pos(&self) -> usize {
+        self.len
+    }
+}
+
+impl<E> Writer<E> for FrameWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), E> {
+        self.crc.update(bytes);
+        self.len += bytes.len();
+
+        let is_body = self
+            .body
+            .as_ref()
+            .is_some_and(|body| body.as_ptr() == bytes.as_ptr() && body.len() == bytes.len());
+
+        if is_body {
+            self.spliced = self.body.take();
+        } else if self.spliced.is_some() {
+            self.tail.extend_from_slice(bytes);
+        } else {
+            self.head.extend_from_slice(bytes);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    async fn collect(root: &Path, options: StreamOptions) -> Vec<u8> {
+        let chunks: Vec<Bytes> = build_stream([root.to_owned()].into_iter(), options)
+            .try_collect()
+            .await
+            .unwrap();
+
+        chunks.concat()
+    }
+
+    #[tokio::test]
+    async fn test_parallel_order() {
+        let root = tempfile::tempdir().unwrap();
+
+        for dir in 0..4 {
+            let dir = root.path().join(dir.to_string());
+            fs::create_dir(&dir).unwrap();
+
+            for file in 0..8 {
+                let len = (dir.as_os_str().len() + file) * 37_000;
+                let contents = format!("{file} {}\n", dir.display()).repeat(len / 20);
+                fs::write(dir.join(file.to_string()), contents).unwrap();
+            }
+        }
+
+        let sequential = StreamOptions {
+            concurrency: 1,
+            ..Default::default()
+        };
+        let parallel = StreamOptions {
+            concurrency: 8,
+            memory_budget: 0,
+            ..Default::default()
+        };
+
+        let expected = collect(root.path(), sequential).await;
+        assert_eq!(expected, collect(root.path(), parallel).await);
+    }
+
+    #[test]
+    fn test_frame_parts() {
+        let data = Bytes::from(vec![7; 100_000]);
+        let message = Message::FileBody { data: data.clone() };
+
+        let parts = frame_parts(&message, &mut Arena::new());
+        assert_eq!(3, parts.len());
+        assert_eq!(data.as_ptr(), parts[1].as_ptr());
+
+        // Byte for byte what serializing the whole message would give.
+        let payload = rkyv::to_bytes::<rancor::Error>(&message).unwrap();
+        let frame = parts.concat();
+        let (header, rest) = frame.split_at(FRAME_HEADER_LEN);
+        assert_eq!(payload.as_slice(), rest);
+        assert_eq!(crc32fast::hash(&payload).to_le_bytes(), header[8..]);
+
+        // Nothing to splice in.
+        let message = Message::FileBody { data: Bytes::new() };
+        assert_eq!(1, frame_parts(&message, &mut Arena::new()).len());
+    }
+
+    #[test]
+    fn test_resume_point() {
+        let point = ResumePoint {
+            path: "/var/log/auth.log".into(),
+            deleted: true,
+        };
+        let written = point.to_string();
+        assert_eq!("deleted:2f7661722f6c6f672f617574682e6c6f67", written);
+        assert_eq!(point, written.parse().unwrap());
+
+        assert!("".parse::<ResumePoint>().is_err());
+        assert!("deleted:".parse::<ResumePoint>().is_err());
+        assert!("/var/log".parse::<ResumePoint>().is_err());
+    }
+
+    #[test]
+    fn test_walk_position() {
+        let roots = [PathBuf::from("/var"), PathBuf::from("/etc")];
+        let walked = [
+            "/var", "/var/a", "/var/a/z", "/var/a.b", "/var/b", "/etc", "/etc/a",
+        ];
+
+        for pair in walked.windows(2) {
+            let (before, after) = (Path::new(pair[0]), Path::new(pair[1]));
+            assert!(walk_position(&roots, before) < walk_position(&roots, after));
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    io,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, task};
+
+/// Limits on how hard a collection may push the host. `None` means
+/// unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Bytes per second sent to the client.
+    pub send_bytes_per_sec: Option<u64>,
+    /// Bytes per second read from disk.
+    pub read_bytes_per_sec: Option<u64>,
+    /// Read calls per second.
+    pub read_ops_per_sec: Option<u64>,
+}
+
+/// The rate limiters every collection shares, which can be adjusted while
+/// collections are running.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    pub send: RateLimiter,
+    pub read_bytes: RateLimiter,
+    pub read_ops: RateLimiter,
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Self {
+        let throttle = Self::default();
+        throttle.set_limits(limits);
+        throttle
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            send_bytes_per_sec: self.send.rate(),
+            read_bytes_per_sec: self.read_bytes.rate(),
+            read_ops_per_sec: self.read_ops.rate(),
+        }
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        self.send.set_rate(limits.send_bytes_per_sec);
+        self.read_bytes.set_rate(limits.read_bytes_per_sec);
+        self.read_ops.set_rate(limits.read_ops_per_sec);
+    }
+}
+
+/// A token bucket holding up to a second's worth of its rate.
+///
+/// Taking more than is available puts the bucket into debt, and the caller
+/// waits until it would have been paid off.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl Bucket {
+    /// What the bucket holds as of `now`, having refilled since it was last
+    /// updated.
+    fn refill(&self, now: Instant) -> f64 {
+        let Some(rate) = self.rate else {
+            return 0.0;
+        };
+
+        let elapsed = self.updated.map_or(Duration::ZERO, |updated| {
+            now.saturating_duration_since(updated)
+        });
+
+        (self.tokens + elapsed.as_secs_f64() * rate as f64).min(rate as f64)
+    }
+}
+
+impl RateLimiter {
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Change the rate. Whatever was in the bucket carries over, up to a
+    /// second's worth of the new rate, and so does any debt, so that setting
+    /// the rate again and again doesn't get around it. A bucket that was
+    /// unlimited starts out full.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.set_rate_at(rate, Instant::now());
+    }
+
+    fn set_rate_at(&self, rate: Option<u64>, now: Instant) {
+        let rate = rate.filter(|&rate| rate > 0);
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let tokens = match (bucket.rate, rate) {
+            (_, None) => 0.0,
+            (None, Some(rate)) => rate as f64,
+            (Some(_), Some(rate)) => bucket.refill(now).min(rate as f64),
+        };
+
+        *bucket = Bucket {
+            rate,
+            tokens,
+            updated: Some(now),
+        };
+    }
+
+    /// Take `amount` from the bucket, waiting for it if need be.
+    pub async fn acquire(&self, amount: u64) {
+        let wait = self.reserve(amount, Instant::now());
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `amount` from the bucket, blocking the thread for it if need be.
+    pub fn acquire_blocking(&self, amount: u64) {
+        let wait = self.reserve(amount, Instant::now());
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Take `amount` from the bucket, and return how long until it's paid
+    /// for.
+    fn reserve(&self, amount: u64, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+
+        bucket.tokens = bucket.refill(now) - amount as f64;
+        bucket.updated = Some(now);
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+}
+
+/// Run `read` on a blocking thread, at idle I/O priority and the lowest CPU
+/// priority if asked, so that collecting gets out of the way of everything
+/// else on the host. Resolves to what it returned, or `None` if it panicked.
+/// Fails if there's no thread to run it on.
+///
+/// Raising a thread's priority back up again takes privileges the agent may
+/// not have, so lowered reads each get a thread of their own that exits
+/// after, rather than one from Tokio's blocking pool that unrelated work
+/// would go on to run at the lowered priority.
+pub fn spawn_reader<F, T>(
+    idle_io: bool,
+    low_cpu: bool,
+    read: F,
+) -> io::Result<impl Future<Output = Option<T>> + Send + 'static>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let run = move || {
+        let _ = sender.send(read());
+    };
+
+    if idle_io || low_cpu {
+        thread::Builder::new()
+            .name("low-priority-reader".to_owned())
+            .spawn(move || {
+                lower_thread_priority(idle_io, low_cpu);
+                run();
+            })?;
+    } else {
+        task::spawn_blocking(run);
+    }
+
+    Ok(async move { receiver.await.ok() })
+}
+
+/// Drop the calling thread to idle I/O priority and the lowest CPU priority,
+/// as asked.
+#[cfg(target_os = "linux")]
+fn lower_thread_priority(idle_io: bool, low_cpu: bool) {
+    use log::warn;
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // On Linux, both of these apply to just the calling thread when given
+    // an ID of 0.
+    if idle_io {
+        let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        let result =
+            unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) };
+
+        if result != 0 {
+            warn!(
+                "Couldn't set idle I/O priority: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    if low_cpu && unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        warn!(
+            "Couldn't lower CPU priority: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Thread priorities are only adjusted on Linux.
+#[cfg(not(target_os = "linux"))]
+fn lower_thread_priority(_idle_io: bool, _low_cpu: bool) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        assert_eq!(Duration::ZERO, limiter.reserve(u64::MAX, start));
+
+        limiter.set_rate(Some(1000));
+        let start = limiter.bucket.lock().unwrap().updated.unwrap();
+
+        // A full second's worth is there to begin with, then it's debt.
+        assert_eq!(Duration::ZERO, limiter.reserve(1000, start));
+        assert_eq!(Duration::from_millis(500), limiter.reserve(500, start));
+
+        // Paid off half a second later, and refilling again after that.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(Duration::ZERO, limiter.reserve(0, later));
+        let later = later + Duration::from_millis(100);
+        assert_eq!(Duration::ZERO, limiter.reserve(100, later));
+
+        limiter.set_rate(None);
+        assert_eq!(Duration::ZERO, limiter.reserve(u64::MAX, later));
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        // Setting the same rate again doesn't top the bucket back up.
+        limiter.set_rate_at(Some(1000), start);
+        assert_eq!(Duration::ZERO, limiter.reserve(1000, start));
+        limiter.set_rate_at(Some(1000), start);
+        assert_eq!(Duration::from_millis(500), limiter.reserve(500, start));
+
+        // Debt carries over to a new rate, and is paid off at that rate.
+        limiter.set_rate_at(Some(100), start);
+        assert_eq!(Duration::from_secs(6), limiter.reserve(100, start));
+
+        // A lower rate caps what's built up.
+        let later = start + Duration::from_secs(60);
+        limiter.set_rate_at(Some(1000), later);
+        limiter.set_rate_at(Some(10), later);
+        assert_eq!(Duration::from_secs(1), limiter.reserve(20, later));
+
+        // Coming from unlimited, there's nothing to carry over.
+        limiter.set_rate_at(None, later);
+        limiter.set_rate_at(Some(1000), later);
+        assert_eq!(Duration::ZERO, limiter.reserve(1000, later));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_spawn_reader() {
+        let nice = || unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+
+        // With a single blocking thread, anything left behind on it would
+        // show up in the next blocking task.
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .max_blocking_threads(1)
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let before = task::spawn_blocking(nice).await.unwrap();
+
+            assert_eq!(
+                Some(before),
+                spawn_reader(false, false, nice).unwrap().await
+            );
+            assert_eq!(Some(19), spawn_reader(false, true, nice).unwrap().await);
+            assert_eq!(before, task::spawn_blocking(nice).await.unwrap());
+
+            let panicked = spawn_reader(true, true, || panic!("unreadable"))
+                .unwrap()
+                .await;
+            assert_eq!(None::<()>, panicked);
+        });
+    }
+}
@@ -0,0 +1,315 @@
+//! HTTPS for the web service, optionally only for clients holding a
+//! certificate from a pinned CA.
+
+use std::{io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use axum::serve::Listener;
+use log::{info, warn};
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+/// How long a client gets to finish its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many finished handshakes can wait for the server to take them.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// The certificate chain the service presents, and its private key.
+pub struct Certificate {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl Certificate {
+    /// Load a PEM certificate chain, leaf first, and its PEM private key.
+    pub fn load(chain: &Path, key: &Path) -> anyhow::Result<Self> {
+        let chain = CertificateDer::pem_file_iter(chain)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("{}: invalid certificate", chain.display()))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .with_context(|| format!("{}: invalid private key", key.display()))?;
+
+        anyhow::ensure!(
+            !chain.is_empty(),
+            "the certificate file holds no certificates"
+        );
+
+        Ok(Self { chain, key })
+    }
+
+    /// A new self-signed certificate for this host, for when the operator
+    /// doesn't have one. Clients can't verify it the usual way, so they pin
+    /// its `fingerprint` instead.
+    pub fn self_signed() -> anyhow::Result<Self> {
+        let mut names = vec!["localhost".to_owned()];
+        names.extend(sysinfo::System::host_name());
+
+        let generated = rcgen::generate_simple_self_signed(names)?;
+
+        Ok(Self {
+            chain: vec![generated.cert.der().clone()],
+            key: PrivatePkcs8KeyDer::from(generated.signing_key.serialize_der()).into(),
+        })
+    }
+
+    /// The SHA-256 fingerprint of the leaf certificate, as `openssl x509
+    /// -fingerprint -sha256` shows it.
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(&self.chain[0])
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+/// The TLS configuration presenting `certificate`, and with a `client_ca`,
+/// only accepting clients holding a certificate it issued.
+pub fn server_config(
+    certificate: Certificate,
+    client_ca: Option<&Path>,
+) -> anyhow::Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("{}: invalid CA certificate", path.display()))?
+            {
+                roots.add(cert?)?;
+            }
+
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certificate.chain, certificate.key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// Accepts TLS connections, handshaking with several clients at once so
+/// that a slow one can't hold up the rest.
+pub struct TlsListener {
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (sender, handshaken) = mpsc::channel(ACCEPT_BACKLOG);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Most likely out of file descriptors, which takes a
+                        // moment to recover from.
+                        warn!("Failed to accept a connection: {e}");
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                if sender.is_closed() {
+                    break;
+                }
+
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => info!("TLS handshake with {addr} failed: {e}"),
+                        Err(_) => info!("TLS handshake with {addr} timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            handshaken,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(accepted) => accepted,
+            // The accepting task only stops once this is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::{Router, routing::get};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    use rustls::{ClientConfig, pki_types::ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// A CA, and a way to issue certificates from it.
+    struct TestCa {
+        cert: rcgen::Certificate,
+        issuer: Issuer<'static, KeyPair>,
+    }
+
+    impl TestCa {
+        fn new() -> Self {
+            let mut params = CertificateParams::new([]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+
+            Self {
+                cert: params.self_signed(&key).unwrap(),
+                issuer: Issuer::new(params, key),
+            }
+        }
+
+        /// A certificate for `localhost`, and its key.
+        fn issue(&self) -> (rcgen::Certificate, KeyPair) {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(["localhost".to_owned()]).unwrap();
+
+            (params.signed_by(&key, &self.issuer).unwrap(), key)
+        }
+
+        fn issue_der(&self) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+            let (cert, key) = self.issue();
+
+            (
+                cert.der().clone(),
+                PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+            )
+        }
+    }
+
+    async fn listen(config: Arc<ServerConfig>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(listener, config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let app = Router::new().route("/", get(|| async { "Hello, World!" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        addr
+    }
+
+    /// Fetch `/` from `addr`, trusting `server` and presenting `client`.
+    async fn fetch(
+        addr: SocketAddr,
+        server: &CertificateDer<'static>,
+        client: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(server.clone()).unwrap();
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+
+        let stream = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await?;
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_self_signed() {
+        let certificate = Certificate::self_signed().unwrap();
+        let cert = certificate.chain[0].clone();
+        let fingerprint = certificate.fingerprint();
+        assert_eq!(32 * 3 - 1, fingerprint.len());
+
+        let addr = listen(server_config(certificate, None).unwrap()).await;
+        let response = fetch(addr, &cert, None).await.unwrap();
+        assert!(response.ends_with("Hello, World!"));
+
+        // Anyone else's certificate isn't trusted.
+        let other = Certificate::self_signed().unwrap();
+        assert_ne!(fingerprint, other.fingerprint());
+        assert!(fetch(addr, &other.chain[0], None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_ca() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = TestCa::new();
+        let (server_cert, server_key) = ca.issue();
+
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        let ca_path = dir.path().join("ca.pem");
+        fs::write(&cert_path, server_cert.pem()).unwrap();
+        fs::write(&key_path, server_key.serialize_pem()).unwrap();
+        fs::write(&ca_path, ca.cert.pem()).unwrap();
+
+        let certificate = Certificate::load(&cert_path, &key_path).unwrap();
+        assert!(Certificate::load(&key_path, &key_path).is_err());
+        assert!(Certificate::load(&cert_path, &cert_path).is_err());
+
+        let addr = listen(server_config(certificate, Some(&ca_path)).unwrap()).await;
+        let trusted = ca.cert.der();
+
+        // Only clients with a certificate from the pinned CA get anywhere.
+        let response = fetch(addr, trusted, Some(ca.issue_der())).await.unwrap();
+        assert!(response.ends_with("Hello, World!"));
+
+        assert!(fetch(addr, trusted, None).await.is_err());
+        let stranger = TestCa::new().issue_der();
+        assert!(fetch(addr, trusted, Some(stranger)).await.is_err());
+    }
+}
@@ -1,46 +1,619 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::IntoFuture,
+    io,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
-use axum::{Router, body::Body, extract::State, response::IntoResponse, routing::get};
-use log::debug;
+use anyhow::Context;
+use axum::{
+    Extension, Json, Router,
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Query, State, connect_info::Connected},
+    http::{HeaderName, StatusCode, header},
+    middleware,
+    response::{AppendHeaders, IntoResponse},
+    routing::{get, post},
+    serve::{IncomingStream, Listener},
+};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use futures::{
+    FutureExt, StreamExt, TryStreamExt,
+    future::{self, BoxFuture},
+};
+use log::{debug, info};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use tokio::task;
 
-use crate::stream::build_stream;
+use crate::{
+    auth::{Auth, Caller, Peer, Scope, authenticate},
+    baseline::Baseline,
+    codec::Compression,
+    digest::DigestAlgorithm,
+    encryption::{encrypt, hex},
+    endpoint::{Bound, Endpoint},
+    hunt::{Targets, hunt},
+    known::KnownHashes,
+    mode::{ModeRule, ModeRules},
+    signing::{parameters, sign},
+    stream::{ResumePoint, StreamOptions, build_stream},
+    throttle::Limits,
+    tls::TlsListener,
+};
 
 #[derive(Debug, Clone)]
 struct ServiceState {
     roots: Vec<PathBuf>,
+    options: StreamOptions,
+    /// Replaced by uploads, and picked up by each collection as it starts.
+    known: Arc<RwLock<Option<Arc<KnownHashes>>>>,
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+/// How many finished or interrupted collections are remembered, so that
+/// they can be resumed.
+const MAX_JOBS: usize = 64;
+
+/// How long a collection can be resumed for, after it was started or last
+/// resumed.
+const JOB_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The response header carrying a collection's job ID.
+const JOB_ID: HeaderName = HeaderName::from_static("x-job-id");
+
+/// The largest baseline manifest a collection can be posted, which is
+/// held in memory while it runs. Enough for a few million files.
+const MAX_BASELINE_LEN: usize = 512 * 1024 * 1024;
+
+/// The largest list of targets a hunt can be asked to look for.
+const MAX_HUNT_LEN: usize = 64 * 1024 * 1024;
+
+/// The largest known-file list that can be uploaded, enough for the
+/// larger published hash sets.
+const MAX_KNOWN_LEN: usize = 512 * 1024 * 1024;
+
+/// What it takes to resume a collection.
+#[derive(Debug)]
+struct Job {
+    /// Who started it, and so may resume it, as long as their scope is
+    /// still the same.
+    caller: String,
+    scope: Scope,
+    /// What they asked for.
+    query: CollectionQuery,
+    /// The hash of the baseline manifest it was collected against, which
+    /// has to be posted again to resume it.
+    baseline: Option<blake3::Hash>,
+    expires: Instant,
+}
+
+/// Recent collections, by job ID, oldest first.
+#[derive(Debug, Default)]
+struct Jobs {
+    jobs: HashMap<String, Job>,
+    order: VecDeque<String>,
+}
+
+impl Jobs {
+    fn insert(&mut self, id: String, job: Job) {
+        let now = Instant::now();
+        self.jobs.retain(|_, job| job.expires > now);
+        self.order.retain(|id| self.jobs.contains_key(id));
+
+        if self.order.len() >= MAX_JOBS
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.jobs.remove(&oldest);
+        }
+
+        self.order.push_back(id.clone());
+        self.jobs.insert(id, job);
+    }
+
+    /// The job with `id`, unless it has expired.
+    fn get_mut(&mut self, id: &str) -> Option<&mut Job> {
+        self.jobs
+            .get_mut(id)
+            .filter(|job| job.expires > Instant::now())
+    }
+}
+
+/// A new job ID, too random to guess.
+fn new_job_id() -> String {
+    let mut id = [0; 16];
+    OsRng.fill_bytes(&mut id);
+
+    hex(&id)
+}
+
+/// Per-request overrides of the service's `StreamOptions`.
+#[derive(Debug, Clone, Deserialize)]
+struct CollectionQuery {
+    /// Comma separated digest algorithms, e.g. `sha256,blake3`.
+    digests: Option<String>,
+    /// `store`, `lz4`, `zstd` or `zstd:<level>`.
+    compression: Option<String>,
+    /// Semicolon separated `<mode>:<pattern>` rules, and optionally a bare
+    /// `<mode>` for everything else, e.g. `full:/etc/**;hash:/usr/**`.
+    modes: Option<String>,
+    /// Whether to send repeated content-defined chunks only once.
+    dedup: Option<bool>,
+    /// Whether to walk each directory's entries sorted by name.
+    sorted: Option<bool>,
+    /// Give the collection a job ID, in the `x-job-id` header, so that it
+    /// can be resumed if it's interrupted. Its walk is sorted, and it sends
+    /// checkpoints along the way. Not available if the agent encrypts.
+    resumable: Option<bool>,
+    /// Carry on with an interrupted collection, with the options it started
+    /// with but the current known-file list. The others are ignored, and a
+    /// baseline has to be posted again.
+    resume: Option<String>,
+    /// The last checkpoint received, as `ResumePoint` writes it. Without one,
+    /// the collection starts over.
+    from: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HuntQuery {
+    /// The algorithm of the targets' digests, when their length can't tell.
+    algorithm: Option<String>,
+    /// Comma separated digest algorithms to report for every match.
+    digests: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KnownQuery {
+    /// The algorithm of an uploaded list's digests, when it can't tell.
+    algorithm: Option<String>,
+}
+
+/// What the current known-file list holds.
+#[derive(Debug, Serialize)]
+struct KnownSummary {
+    algorithm: &'static str,
+    len: usize,
+}
+
+impl From<&KnownHashes> for KnownSummary {
+    fn from(known: &KnownHashes) -> Self {
+        Self {
+            algorithm: known.algorithm().name(),
+            len: known.len(),
+        }
+    }
+}
+
+/// Serve on every one of `endpoints` at once, to those `auth` lets in. TCP
+/// endpoints serve HTTPS when given a `tls` configuration.
+pub async fn start<IR, R>(
+    endpoints: &[Endpoint],
+    root: IR,
+    options: StreamOptions,
+    tls: Option<Arc<ServerConfig>>,
+    auth: Auth,
+) -> anyhow::Result<()>
+where
+    IR: IntoIterator<Item = R>,
+    R: AsRef<Path>,
+{
+    anyhow::ensure!(!endpoints.is_empty(), "nowhere to listen");
+
+    let app = router(root, options, auth);
+    let mut servers = Vec::new();
+
+    // Bind everything before serving anything, so that one endpoint failing
+    // doesn't leave the others half up.
+    for endpoint in endpoints {
+        let bound = endpoint
+            .bind()
+            .with_context(|| format!("failed to listen on {endpoint}"))?;
+
+        servers.push(match bound {
+            Bound::Tcp(listener) => {
+                info!("Listening on {}", listener.local_addr()?);
+                match &tls {
+                    Some(config) => serve_on(TlsListener::new(listener, config.clone())?, &app),
+                    None => serve_on(listener, &app),
+                }
+            }
+            #[cfg(unix)]
+            Bound::Unix(listener) => {
+                info!("Listening on {endpoint}");
+                serve_on(listener, &app)
+            }
+        });
+    }
+
+    future::try_join_all(servers).await?;
+
+    Ok(())
+}
+
+/// Serve requests on an already bound listener.
+pub async fn serve<L, IR, R>(
+    listener: L,
+    root: IR,
+    options: StreamOptions,
+    auth: Auth,
+) -> anyhow::Result<()>
+where
+    L: Listener,
+    L::Addr: fmt::Debug,
+    Peer: for<'a> Connected<IncomingStream<'a, L>>,
+    IR: IntoIterator<Item = R>,
+    R: AsRef<Path>,
+{
+    serve_on(listener, &router(root, options, auth)).await?;
+
+    Ok(())
 }
 
-pub async fn start<IR, R>(port: u16, root: IR) -> anyhow::Result<()>
+fn serve_on<L>(listener: L, app: &Router) -> BoxFuture<'static, io::Result<()>>
+where
+    L: Listener,
+    L::Addr: fmt::Debug,
+    Peer: for<'a> Connected<IncomingStream<'a, L>>,
+{
+    axum::serve(
+        listener,
+        app.clone().into_make_service_with_connect_info::<Peer>(),
+    )
+    .into_future()
+    .boxed()
+}
+
+fn router<IR, R>(root: IR, options: StreamOptions, auth: Auth) -> Router
 where
     IR: IntoIterator<Item = R>,
     R: AsRef<Path>,
 {
     let state = ServiceState {
         roots: root.into_iter().map(|p| p.as_ref().to_path_buf()).collect(),
+        known: Arc::new(RwLock::new(options.known.clone())),
+        jobs: Arc::default(),
+        options,
     };
 
-    let app = Router::new()
+    Router::new()
         .route("/", get(|| async { "Hello, World!" }))
-        .route("/fs", get(download_filesystem))
-        .with_state(state);
+        .route(
+            "/fs",
+            get(download_filesystem)
+                .post(download_changes)
+                .layer(DefaultBodyLimit::max(MAX_BASELINE_LEN)),
+        )
+        .route("/throttle", get(get_throttle).put(set_throttle))
+        .route(
+            "/hunt",
+            post(hunt_files).layer(DefaultBodyLimit::max(MAX_HUNT_LEN)),
+        )
+        .route(
+            "/known",
+            get(get_known)
+                .put(set_known)
+                .delete(clear_known)
+                .layer(DefaultBodyLimit::max(MAX_KNOWN_LEN)),
+        )
+        .layer(middleware::from_fn_with_state(Arc::new(auth), authenticate))
+        .with_state(state)
+}
 
-    let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
-    let listener = tokio::net::TcpListener::bind(address).await?;
+async fn download_filesystem(
+    State(state): State<ServiceState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<CollectionQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    start_collection(state, caller, query, None)
+}
 
-    axum::serve(listener, app).await?;
+/// Start a collection, or resume one, against the `baseline` posted and
+/// its hash if there is one.
+fn start_collection(
+    state: ServiceState,
+    caller: Caller,
+    query: CollectionQuery,
+    baseline: Option<(Arc<Baseline>, blake3::Hash)>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    debug!("{:#?}", state.roots);
 
-    Ok(())
+    let mut options = state.options;
+
+    let (query, id) = match query.resume {
+        Some(id) => {
+            options.resume_from = query
+                .from
+                .map(|from| from.parse::<ResumePoint>())
+                .transpose()
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+            let mut jobs = state.jobs.lock().unwrap();
+            // Someone else's job, or one started under another scope, is as
+            // good as missing.
+            let job = jobs
+                .get_mut(&id)
+                .filter(|job| job.caller == caller.name && job.scope == *caller.scope())
+                .ok_or((
+                    StatusCode::NOT_FOUND,
+                    format!("there's no job `{id}` to resume"),
+                ))?;
+
+            if job.baseline != baseline.as_ref().map(|(_, hash)| *hash) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("job `{id}` has to be resumed against the baseline it started with"),
+                ));
+            }
+
+            job.expires = Instant::now() + JOB_LIFETIME;
+
+            info!("Resuming {id} for {}", caller.name);
+
+            (job.query.clone(), Some(id))
+        }
+        None if query.from.is_some() => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "`from` only makes sense with `resume`".to_owned(),
+            ));
+        }
+        None => (query, None),
+    };
+
+    // Checkpoints count plaintext, which an encrypted capture can't be cut
+    // and spliced back together at.
+    if query.resumable == Some(true) && !options.recipients.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "encrypted collections can't be resumed".to_owned(),
+        ));
+    }
+
+    let roots = caller.roots_within(&state.roots);
+    if roots.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("{} may not collect any of the agent's roots", caller.name),
+        ));
+    }
+
+    options.include = caller.include();
+
+    if let Some(digests) = &query.digests {
+        options.digests = DigestAlgorithm::parse_list(digests)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    }
+
+    if let Some(compression) = &query.compression {
+        options.compression = compression
+            .parse::<Compression>()
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    }
+
+    if let Some(modes) = &query.modes {
+        let rules = modes
+            .split(';')
+            .map(str::parse::<ModeRule>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+        options.modes =
+            ModeRules::new(rules).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    }
+
+    if let Some(dedup) = query.dedup {
+        options.dedup = dedup;
+    }
+
+    if let Some(sorted) = query.sorted {
+        options.sorted = sorted;
+    }
+
+    options.known = state.known.read().unwrap().clone();
+
+    let id = match id {
+        Some(id) => Some(id),
+        None if query.resumable == Some(true) => {
+            let id = new_job_id();
+            let job = Job {
+                caller: caller.name.clone(),
+                scope: caller.scope().clone(),
+                baseline: baseline.as_ref().map(|(_, hash)| *hash),
+                query,
+                expires: Instant::now() + JOB_LIFETIME,
+            };
+            state.jobs.lock().unwrap().insert(id.clone(), job);
+
+            info!("Starting {id} for {}", caller.name);
+
+            Some(id)
+        }
+        None => None,
+    };
+
+    options.baseline = baseline.map(|(baseline, _)| baseline);
+    options.job = id.clone();
+
+    Ok(collect(roots, id, options))
 }
 
-async fn download_filesystem(State(state): State<ServiceState>) -> impl IntoResponse {
-    debug!("{:#?}", state.roots);
+/// Stream a collection, along with its job ID if it has one, signed if
+/// there's a key and then encrypted if there are any recipients.
+fn collect(roots: Vec<PathBuf>, job: Option<String>, options: StreamOptions) -> impl IntoResponse {
+    let recipients = options.recipients.clone();
+    let signing = options
+        .signing_key
+        .clone()
+        .map(|key| (key, parameters(&roots, &options)));
 
-    let stream = build_stream(state.roots.into_iter());
+    let mut stream = build_stream(roots.into_iter(), options)
+        .into_stream()
+        .boxed();
 
     debug!("Built stream");
 
-    Body::from_stream(stream)
+    if let Some((key, parameters)) = signing {
+        stream = sign(stream, key, parameters).into_stream().boxed();
+    }
+
+    if !recipients.is_empty() {
+        stream = encrypt(stream, recipients).into_stream().boxed();
+    }
+
+    (
+        AppendHeaders(job.map(|job| (JOB_ID, job))),
+        Body::from_stream(stream),
+    )
+}
+
+/// Collect only what changed since the baseline whose manifest is posted.
+async fn download_changes(
+    State(state): State<ServiceState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<CollectionQuery>,
+    body: Bytes,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let hash = blake3::hash(&body);
+    let baseline = task::spawn_blocking(move || Baseline::parse(&body))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+
+    info!("Collecting against a baseline of {} files", baseline.len());
+
+    start_collection(state, caller, query, Some((Arc::new(baseline), hash)))
+}
+
+/// The limits every collection is currently held to.
+async fn get_throttle(State(state): State<ServiceState>) -> Json<Limits> {
+    Json(state.options.throttle.limits())
+}
+
+/// Change the limits, taking effect on collections already running too.
+async fn set_throttle(
+    State(state): State<ServiceState>,
+    Extension(caller): Extension<Caller>,
+    Json(limits): Json<Limits>,
+) -> Result<Json<Limits>, (StatusCode, String)> {
+    require_admin(&caller)?;
+
+    debug!("Throttling to {limits:?} for {}", caller.name);
+    state.options.throttle.set_limits(limits);
+
+    Ok(Json(limits))
+}
+
+/// Turn away callers who may not change what every collection shares.
+fn require_admin(caller: &Caller) -> Result<(), (StatusCode, String)> {
+    match caller.is_admin() {
+        true => Ok(()),
+        false => Err((
+            StatusCode::FORBIDDEN,
+            format!("{} may only change what they collect", caller.name),
+        )),
+    }
+}
+
+/// Hunt the roots for the targets in the body, in the form `Targets::parse`
+/// takes, answering with each match as a line of JSON as soon as it's found.
+async fn hunt_files(
+    State(state): State<ServiceState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<HuntQuery>,
+    body: String,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let algorithm = query
+        .algorithm
+        .map(|algorithm| algorithm.parse::<DigestAlgorithm>())
+        .transpose()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let targets = Targets::parse(&body, algorithm)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+
+    let mut options = state.options;
+    options.include = caller.include();
+
+    if let Some(digests) = query.digests {
+        options.digests = DigestAlgorithm::parse_list(&digests)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    }
+
+    let roots = caller.roots_within(&state.roots);
+    if roots.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("{} may not hunt in any of the agent's roots", caller.name),
+        ));
+    }
+
+    info!("Hunting for {} digests for {}", targets.len(), caller.name);
+
+    let matches = hunt(roots.into_iter(), Arc::new(targets), options)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))?
+        .map(|found| {
+            let mut line = serde_json::to_vec(&found)?;
+            line.push(b'\n');
+
+            Ok::<_, serde_json::Error>(line)
+        });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(matches),
+    ))
+}
+
+async fn get_known(State(state): State<ServiceState>) -> Json<Option<KnownSummary>> {
+    Json(
+        state
+            .known
+            .read()
+            .unwrap()
+            .as_deref()
+            .map(KnownSummary::from),
+    )
+}
+
+/// Replace the known-file list with an uploaded one, in any of the forms
+/// `KnownHashes::parse` takes. Collections already running keep the list
+/// they started with.
+async fn set_known(
+    State(state): State<ServiceState>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<KnownQuery>,
+    body: Bytes,
+) -> Result<Json<KnownSummary>, (StatusCode, String)> {
+    require_admin(&caller)?;
+
+    let algorithm = query
+        .algorithm
+        .map(|algorithm| algorithm.parse::<DigestAlgorithm>())
+        .transpose()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    let known = task::spawn_blocking(move || KnownHashes::parse(&body, algorithm))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+
+    info!("Loaded {} known {} digests", known.len(), known.algorithm());
+
+    let summary = KnownSummary::from(&known);
+    *state.known.write().unwrap() = Some(Arc::new(known));
+
+    Ok(Json(summary))
+}
+
+async fn clear_known(
+    State(state): State<ServiceState>,
+    Extension(caller): Extension<Caller>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_admin(&caller)?;
+
+    *state.known.write().unwrap() = None;
+
+    Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,17 @@
+#![no_main]
+
+use std::io::{self, Read};
+
+use decoder::reader::Entries;
+use libfuzzer_sys::fuzz_target;
+
+// Run arbitrary bytes through frame parsing, message validation and
+// decompression, the same way every decoder command does.
+fuzz_target!(|data: &[u8]| {
+    let mut entries = Entries::new(data);
+
+    while let Ok(Some(_)) = entries.next_entry() {
+        let _ = io::copy(&mut entries.contents().take(1 << 20), &mut io::sink());
+        let _ = entries.finish();
+    }
+});
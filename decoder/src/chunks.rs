@@ -0,0 +1,181 @@
+//! The chunks of a deduplicated stream, kept for the files that refer back
+//! to them.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use agentfs::{
+    chunking::{ChunkHash, MAX_CHUNK},
+    codec::Codec,
+    digest::{DigestAlgorithm, Hashers},
+};
+use lz4_flex::frame::FrameDecoder;
+
+/// Every chunk seen so far, still compressed, spilled to a temporary file
+/// since a stream can repeat data from anywhere before it.
+#[derive(Debug, Default)]
+pub struct ChunkStore {
+    /// Created along with the first chunk.
+    file: Option<File>,
+    len: u64,
+    chunks: HashMap<ChunkHash, StoredChunk>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StoredChunk {
+    offset: u64,
+    len: usize,
+    codec: Codec,
+}
+
+impl ChunkStore {
+    /// Keep a chunk if it matches its hash, unless an intact copy already
+    /// is, so that a damaged or forged copy can't stand in for a good one.
+    /// Returns its contents, or `None` if it's damaged.
+    pub fn insert(
+        &mut self,
+        hash: ChunkHash,
+        codec: Codec,
+        data: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let contents = decompress(&hash, codec, data);
+
+        let (Some(_), Entry::Vacant(entry)) = (&contents, self.chunks.entry(hash)) else {
+            return Ok(contents);
+        };
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(tempfile::tempfile()?),
+        };
+
+        file.seek(SeekFrom::Start(self.len))?;
+        file.write_all(data)?;
+
+        entry.insert(StoredChunk {
+            offset: self.len,
+            len: data.len(),
+            codec,
+        });
+        self.len += data.len() as u64;
+
+        Ok(contents)
+    }
+
+    pub fn contains(&self, hash: &ChunkHash) -> bool {
+        self.chunks.contains_key(hash)
+    }
+
+    /// The decompressed contents of a chunk, or `None` if it was never seen
+    /// or doesn't match its hash.
+    pub fn get(&mut self, hash: &ChunkHash) -> io::Result<Option<Vec<u8>>> {
+        let (Some(chunk), Some(file)) = (self.chunks.get(hash), &mut self.file) else {
+            return Ok(None);
+        };
+
+        let mut data = vec![0; chunk.len];
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        file.read_exact(&mut data)?;
+
+        Ok(decompress(hash, chunk.codec, &data))
+    }
+}
+
+/// Decompress a chunk, checking it against its hash. Damage yields `None`,
+/// as does anything longer than a chunk can be.
+pub fn decompress(hash: &ChunkHash, codec: Codec, data: &[u8]) -> Option<Vec<u8>> {
+    let limit = u64::from(MAX_CHUNK) + 1;
+    let mut contents = Vec::new();
+
+    match codec {
+        Codec::Store => contents.extend_from_slice(data),
+        Codec::Lz4 => {
+            FrameDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut contents)
+                .ok()?;
+        }
+        Codec::Zstd => {
+            zstd::Decoder::new(data)
+                .ok()?
+                .take(limit)
+                .read_to_end(&mut contents)
+                .ok()?;
+        }
+    }
+
+    if contents.len() > MAX_CHUNK as usize {
+        return None;
+    }
+
+    let mut hashers = Hashers::new(&[DigestAlgorithm::Blake3]);
+    hashers.update(&contents);
+
+    (hashers.finalize()[0].value == hash).then_some(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use agentfs::codec::{Compression, Encoder};
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_chunk_store() {
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(100);
+        let hash = blake3_of(&text);
+        let mut encoder = Encoder::new(Codec::Zstd, Compression::Zstd { level: 3 }).unwrap();
+        let compressed = [
+            encoder.encode(Bytes::from(text.clone())).unwrap(),
+            encoder.finish().unwrap(),
+        ]
+        .concat();
+
+        let mut store = ChunkStore::default();
+        assert_eq!(None, store.get(&hash).unwrap());
+
+        // Doesn't match its hash, so it isn't kept.
+        assert_eq!(None, store.insert([0; 32], Codec::Store, b"junk").unwrap());
+        assert!(!store.contains(&[0; 32]));
+
+        // A bad copy doesn't keep out the good one after it.
+        assert_eq!(None, store.insert(hash, Codec::Store, b"forged").unwrap());
+        assert_eq!(
+            Some(text.clone()),
+            store.insert(hash, Codec::Zstd, &compressed).unwrap()
+        );
+        store.insert(hash, Codec::Store, b"ignored").unwrap();
+
+        assert_eq!(Some(text), store.get(&hash).unwrap());
+        assert_eq!(None, store.get(&[0; 32]).unwrap());
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let zeroes = vec![0; MAX_CHUNK as usize + 1];
+        let compressed = zstd::encode_all(zeroes.as_slice(), 3).unwrap();
+        assert!(compressed.len() < 1024);
+        assert_eq!(
+            None,
+            decompress(&blake3_of(&zeroes), Codec::Zstd, &compressed)
+        );
+
+        let largest = &zeroes[..MAX_CHUNK as usize];
+        let compressed = zstd::encode_all(largest, 3).unwrap();
+        assert_eq!(
+            Some(largest.to_vec()),
+            decompress(&blake3_of(largest), Codec::Zstd, &compressed)
+        );
+    }
+
+    fn blake3_of(data: &[u8]) -> ChunkHash {
+        let mut hashers = Hashers::new(&[DigestAlgorithm::Blake3]);
+        hashers.update(data);
+
+        hashers.finalize().remove(0).value.try_into().unwrap()
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use agentfs::{mode::CollectionMode, stream::EntryMetadata};
+use clap::ValueEnum;
+use tar::{EntryType, Header};
+use time::{OffsetDateTime, PrimitiveDateTime};
+use zip::{CompressionMethod, DateTime, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    extract::relative_path,
+    reader::{Entries, Entry, EntryKind},
+};
+
+/// Largest value that fits in a ustar header's 8 byte numeric fields.
+const USTAR_MAX_ID: u64 = 0o7777777;
+/// Largest value that fits in a ustar header's 12 byte size field.
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ArchiveFormat {
+    /// POSIX pax tar, keeping metadata and long or non-UTF-8 names
+    Tar,
+    /// Zip, with non-UTF-8 names converted lossily
+    Zip,
+}
+
+/// Transcode a capture into a standard archive.
+///
+/// Entries are written as they're read, so neither side needs to be seekable
+/// and no temporary space is used. Files whose contents weren't collected are
+/// left out.
+///
+/// Returns the files whose contents came up short of the length a tar header
+/// had already promised, and were padded out with zeroes.
+pub fn convert<R: Read>(
+    entries: &mut Entries<R>,
+    output: impl Write,
+    format: ArchiveFormat,
+) -> anyhow::Result<Vec<PathBuf>> {
+    match format {
+        ArchiveFormat::Tar => to_tar(entries, output),
+        ArchiveFormat::Zip => to_zip(entries, output).map(|()| Vec::new()),
+    }
+}
+
+fn to_tar<R: Read>(entries: &mut Entries<R>, output: impl Write) -> anyhow::Result<Vec<PathBuf>> {
+    let mut builder = tar::Builder::new(output);
+    let mut padded = Vec::new();
+
+    while let Some(entry) = entries.next_entry()? {
+        let relative = relative_path(&entry.path);
+
+        if relative.as_os_str().is_empty() || !is_archivable(entry.kind) {
+            continue;
+        }
+
+        let (entry_type, len) = match entry.kind {
+            EntryKind::File { len, .. } => (EntryType::Regular, len),
+            EntryKind::Directory => (EntryType::Directory, 0),
+            EntryKind::Deleted => unreachable!("deleted files aren't archivable"),
+        };
+
+        let mut header = Header::new_ustar();
+        header.set_entry_type(entry_type);
+        header.set_size(len.min(USTAR_MAX_SIZE));
+        header.set_mode(entry.metadata.mode);
+        header.set_uid(u64::from(entry.metadata.uid).min(USTAR_MAX_ID));
+        header.set_gid(u64::from(entry.metadata.gid).min(USTAR_MAX_ID));
+        header.set_mtime(entry.metadata.mtime.max(0) as u64);
+
+        let mut records = Vec::new();
+        let path = relative.as_os_str().as_encoded_bytes();
+        let is_utf8 = str::from_utf8(path).is_ok();
+
+        if !is_utf8 || header.set_path(&relative).is_err() {
+            if !is_utf8 {
+                pax_record(&mut records, "hdrcharset", b"BINARY");
+            }
+
+            pax_record(&mut records, "path", path);
+
+            // Readers without pax support still get a recognisable name.
+            let name = &mut header.as_ustar_mut().unwrap().name;
+            let len = path.len().min(name.len());
+            name[..len].copy_from_slice(&path[..len]);
+        }
+
+        pax_records(&mut records, &entry.metadata, len);
+
+        if !records.is_empty() {
+            let mut pax = Header::new_ustar();
+            pax.set_entry_type(EntryType::XHeader);
+            pax.set_path("PaxHeader")?;
+            pax.set_size(records.len() as u64);
+            pax.set_cksum();
+
+            builder.append(&pax, records.as_slice())?;
+        }
+
+        header.set_cksum();
+
+        match entry.kind {
+            EntryKind::File { len, .. } => {
+                let mut contents = ExactLen::new(entries.contents(), len);
+                builder.append(&header, &mut contents)?;
+
+                if contents.short {
+                    padded.push(entry.path);
+                }
+            }
+            EntryKind::Directory => builder.append(&header, io::empty())?,
+            EntryKind::Deleted => unreachable!("deleted files aren't archivable"),
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+
+    Ok(padded)
+}
+
+/// Extended header records for anything a plain ustar header would lose.
+fn pax_records(records: &mut Vec<u8>, metadata: &EntryMetadata, len: u64) {
+    if metadata.mtime_nsec != 0 || metadata.mtime < 0 {
+        let mtime = pax_time(metadata.mtime, metadata.mtime_nsec);
+        pax_record(records, "mtime", mtime.as_bytes());
+    }
+
+    if u64::from(metadata.uid) > USTAR_MAX_ID {
+        pax_record(records, "uid", metadata.uid.to_string().as_bytes());
+    }
+
+    if u64::from(metadata.gid) > USTAR_MAX_ID {
+        pax_record(records, "gid", metadata.gid.to_string().as_bytes());
+    }
+
+    if len > USTAR_MAX_SIZE {
+        pax_record(records, "size", len.to_string().as_bytes());
+    }
+}
+
+/// A pax timestamp, in decimal seconds. The nanoseconds count forward from
+/// `secs` even when it's negative, so -1 s and 0.5 s is `-0.500000000`.
+fn pax_time(secs: i64, nsec: u32) -> String {
+    let nanos = i128::from(secs) * 1_000_000_000 + i128::from(nsec);
+    let sign = if nanos < 0 { "-" } else { "" };
+    let (secs, nsec) = (nanos.abs() / 1_000_000_000, nanos.abs() % 1_000_000_000);
+
+    match nsec {
+        0 => format!("{sign}{secs}"),
+        _ => format!("{sign}{secs}.{nsec:09}"),
+    }
+}
+
+/// Append a single `"<len> <key>=<value>\n"` record, where `len` counts the
+/// whole record including its own digits.
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+
+    while len.to_string().len() + rest != len {
+        len += 1;
+    }
+
+    records.extend_from_slice(format!("{len} {key}=").as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn to_zip<R: Read>(entries: &mut Entries<R>, output: impl Write) -> anyhow::Result<()> {
+    let mut zip = ZipWriter::new_stream(output);
+
+    while let Some(Entry {
+        kind,
+        path,
+        metadata,
+    }) = entries.next_entry()?
+    {
+        let relative = relative_path(&path);
+
+        if relative.as_os_str().is_empty() || !is_archivable(kind) {
+            continue;
+        }
+
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(metadata.mode);
+
+        if let Some(mtime) = zip_time(metadata.mtime) {
+            options = options.last_modified_time(mtime);
+        }
+
+        match kind {
+            EntryKind::File { len, .. } => {
+                zip.start_file(name, options.large_file(len >= u64::from(u32::MAX)))?;
+                io::copy(&mut entries.contents(), &mut zip)?;
+            }
+            EntryKind::Directory => zip.add_directory(name, options)?,
+            EntryKind::Deleted => unreachable!("deleted files aren't archivable"),
+        }
+    }
+
+    zip.finish()?.into_inner().flush()?;
+
+    Ok(())
+}
+
+/// Directories, and files whose contents were collected.
+fn is_archivable(kind: EntryKind) -> bool {
+    match kind {
+        EntryKind::File { mode, .. } => mode == CollectionMode::Full,
+        EntryKind::Directory => true,
+        EntryKind::Deleted => false,
+    }
+}
+
+/// Zip timestamps are DOS local times between 1980 and 2107.
+fn zip_time(mtime: i64) -> Option<DateTime> {
+    let time = OffsetDateTime::from_unix_timestamp(mtime).ok()?;
+
+    DateTime::try_from(PrimitiveDateTime::new(time.date(), time.time())).ok()
+}
+
+/// Yields exactly `len` bytes, truncating a longer reader and padding a
+/// shorter one with zeroes. A tar header's size has to be written before the
+/// contents, so this keeps the archive valid if a file changed mid-capture.
+struct ExactLen<R> {
+    inner: R,
+    remaining: u64,
+    short: bool,
+}
+
+impl<R: Read> ExactLen<R> {
+    fn new(inner: R, len: u64) -> Self {
+        Self {
+            inner,
+            remaining: len,
+            short: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ExactLen<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+
+        if max == 0 {
+            return Ok(0);
+        }
+
+        let read = if self.short {
+            0
+        } else {
+            self.inner.read(&mut buf[..max])?
+        };
+
+        let read = if read == 0 {
+            self.short = true;
+            buf[..max].fill(0);
+            max
+        } else {
+            read
+        };
+
+        self.remaining -= read as u64;
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pax_record() {
+        let mut records = Vec::new();
+        pax_record(&mut records, "path", b"foo");
+        assert_eq!(b"12 path=foo\n".as_slice(), records);
+
+        let mut records = Vec::new();
+        pax_record(&mut records, "a", b"bcd");
+        assert_eq!(b"8 a=bcd\n".as_slice(), records);
+
+        // Counting the length's own digits pushes it from 13 to 14.
+        let mut records = Vec::new();
+        pax_record(&mut records, "path", b"hello");
+        assert_eq!(b"14 path=hello\n".as_slice(), records);
+    }
+
+    #[test]
+    fn test_pax_time() {
+        assert_eq!("1700000000.000000001", pax_time(1_700_000_000, 1));
+        assert_eq!("-0.500000000", pax_time(-1, 500_000_000));
+        assert_eq!("-1.500000000", pax_time(-2, 500_000_000));
+        assert_eq!("-86400", pax_time(-86400, 0));
+    }
+}
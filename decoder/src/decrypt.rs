@@ -0,0 +1,208 @@
+//! Decryption of captures an agent encrypted to us, as they're read.
+
+use std::io::{self, Cursor, Read};
+
+use agentfs::encryption::{Identity, MAGIC, SEGMENT_LEN, SegmentDecryptor, TAG_LEN, open_header};
+
+/// A sealed segment, other than the last.
+const SEALED_LEN: usize = SEGMENT_LEN + TAG_LEN;
+
+/// Decrypt `reader` with `identity` if the capture it holds is encrypted,
+/// or pass it through untouched if it isn't.
+pub fn decrypt(
+    mut reader: Box<dyn Read>,
+    identity: Option<&Identity>,
+) -> anyhow::Result<Box<dyn Read>> {
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    (&mut reader)
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+
+    let encrypted = magic == MAGIC;
+    let reader = Box::new(Cursor::new(magic).chain(reader));
+
+    match identity {
+        _ if !encrypted => Ok(reader),
+        Some(identity) => Ok(Box::new(Decryptor::new(reader, identity)?)),
+        None => anyhow::bail!("the capture is encrypted, pass `--identity` to decrypt it"),
+    }
+}
+
+/// Reads the framed stream out of an encrypted one.
+///
+/// Any segment that fails to decrypt is an error rather than a gap to skip,
+/// since nothing after it can be trusted to be what the agent sent. That
+/// includes a capture that was cut off, which can't be told apart from one
+/// that was cut short on purpose.
+pub struct Decryptor<R> {
+    inner: R,
+    /// `None` once the last segment has been decrypted.
+    segments: Option<SegmentDecryptor>,
+    /// Sealed bytes read ahead of the next segment.
+    sealed: Vec<u8>,
+    plain: Vec<u8>,
+    position: usize,
+    /// The next segment's index, for errors.
+    index: u64,
+}
+
+impl<R: Read> Decryptor<R> {
+    /// Read the header, failing if the capture isn't encrypted to
+    /// `identity`.
+    pub fn new(mut inner: R, identity: &Identity) -> io::Result<Self> {
+        let segments = open_header(&mut inner, identity)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the capture isn't encrypted to `{}`", identity.recipient()),
+            )
+        })?;
+
+        Ok(Self {
+            inner,
+            segments: Some(segments),
+            sealed: Vec::with_capacity(SEALED_LEN + 1),
+            plain: Vec::new(),
+            position: 0,
+            index: 0,
+        })
+    }
+
+    /// Decrypt the next segment into `plain`, if there is one.
+    fn next_segment(&mut self) -> io::Result<()> {
+        if self.segments.is_none() {
+            return Ok(());
+        }
+
+        // Read a byte past a whole segment, to tell whether it's the last.
+        while self.sealed.len() <= SEALED_LEN {
+            let filled = self.sealed.len();
+            self.sealed.resize(SEALED_LEN + 1, 0);
+
+            let read = self.inner.read(&mut self.sealed[filled..]);
+            self.sealed.truncate(filled + *read.as_ref().unwrap_or(&0));
+
+            match read {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let index = self.index;
+        self.index += 1;
+
+        self.plain = if self.sealed.len() > SEALED_LEN {
+            let segments = self.segments.as_mut().unwrap();
+            let plain = segments
+                .decrypt_next(&self.sealed[..SEALED_LEN])
+                .map_err(|_| damaged(format!("segment {index} of the capture is damaged")))?;
+            self.sealed.drain(..SEALED_LEN);
+
+            plain
+        } else {
+            let segments = self.segments.take().unwrap();
+            segments.decrypt_last(self.sealed.as_slice()).map_err(|_| {
+                damaged(format!(
+                    "segment {index} of the capture is damaged, or the capture was cut off"
+                ))
+            })?
+        };
+        self.position = 0;
+
+        Ok(())
+    }
+}
+
+fn damaged(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.plain.len() {
+            self.next_segment()?;
+        }
+
+        let plain = &self.plain[self.position..];
+        let len = plain.len().min(buf.len());
+        buf[..len].copy_from_slice(&plain[..len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agentfs::encryption::encrypt;
+    use bytes::Bytes;
+    use futures::{TryStreamExt, executor::block_on, stream};
+
+    use super::*;
+
+    fn encrypted(plain: &[u8], identities: &[&Identity]) -> Vec<u8> {
+        // Pieces of all sorts of sizes, as frames would come.
+        let pieces: Vec<io::Result<Bytes>> = plain
+            .chunks(7_919)
+            .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+            .collect();
+        let recipients = identities.iter().map(|i| i.recipient()).collect();
+
+        let sealed: Vec<Bytes> =
+            block_on(encrypt(stream::iter(pieces), recipients).try_collect()).unwrap();
+
+        sealed.concat()
+    }
+
+    fn decrypted(sealed: &[u8], identity: &Identity) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        decrypt(Box::new(Cursor::new(sealed.to_vec())), Some(identity))
+            .map_err(io::Error::other)?
+            .read_to_end(&mut plain)?;
+
+        Ok(plain)
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let (alice, bob, eve) = (
+            Identity::generate(),
+            Identity::generate(),
+            Identity::generate(),
+        );
+
+        for len in [0, 1, SEGMENT_LEN, 3 * SEGMENT_LEN, 3 * SEGMENT_LEN + 5] {
+            let plain: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+            let sealed = encrypted(&plain, &[&alice, &bob]);
+
+            assert_eq!(plain, decrypted(&sealed, &alice).unwrap());
+            assert_eq!(plain, decrypted(&sealed, &bob).unwrap());
+            assert!(decrypted(&sealed, &eve).is_err());
+        }
+
+        let plain = vec![7; 2 * SEGMENT_LEN + 100];
+        let sealed = encrypted(&plain, &[&alice]);
+
+        // Tampered with.
+        let mut tampered = sealed.clone();
+        tampered[sealed.len() / 2] ^= 1;
+        assert!(decrypted(&tampered, &alice).is_err());
+
+        // Cut off, even at a segment boundary.
+        let header = sealed.len() - 2 * SEALED_LEN - (100 + TAG_LEN);
+        for len in [sealed.len() - 1, header + SEALED_LEN, header] {
+            assert!(decrypted(&sealed[..len], &alice).is_err());
+        }
+
+        // Unencrypted captures pass through, with or without an identity.
+        let mut through = Vec::new();
+        decrypt(Box::new(Cursor::new(b"AGFS...".to_vec())), None)
+            .unwrap()
+            .read_to_end(&mut through)
+            .unwrap();
+        assert_eq!(b"AGFS...", through.as_slice());
+
+        assert!(decrypt(Box::new(Cursor::new(sealed)), None).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use agentfs::mode::CollectionMode;
+use filesystem_iter::{GlobSet, glob_set};
+
+use crate::reader::{Entries, EntryKind};
+
+/// Decides which captured paths are wanted, using the same glob rules as the
+/// agent's own filesystem iterator.
+#[derive(Debug)]
+pub struct Selection {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Selection {
+    /// An empty include list selects everything that isn't excluded.
+    pub fn new<I, E, S>(include: I, exclude: E) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        E: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let include = glob_set(include)?;
+
+        Ok(Self {
+            include: (!include.is_empty()).then_some(include),
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    pub fn is_match(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+
+        self.include.as_ref().is_none_or(|set| set.is_match(path)) && !self.exclude.is_match(path)
+    }
+}
+
+/// Write every selected file and directory in the capture underneath `output`.
+///
+/// Bodies of files that aren't selected are skipped over without being
+/// decompressed. Files whose contents weren't collected are left out rather
+/// than written empty.
+pub fn extract<R: Read>(
+    entries: &mut Entries<R>,
+    output: &Path,
+    selection: &Selection,
+) -> anyhow::Result<()> {
+    while let Some(entry) = entries.next_entry()? {
+        if !selection.is_match(&entry.path) {
+            continue;
+        }
+
+        let destination = output.join(relative_path(&entry.path));
+
+        match entry.kind {
+            EntryKind::Directory => fs::create_dir_all(destination)?,
+            EntryKind::File { mode, .. } if mode != CollectionMode::Full => {}
+            EntryKind::Deleted => {}
+            EntryKind::File { .. } => {
+                if let Some(parent) = destination.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                let mut file = BufWriter::new(File::create(destination)?);
+                io::copy(&mut entries.contents(), &mut file)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Turn a captured absolute path into one that is safe to join onto the
+/// output directory, or to use inside an archive. Anything that could climb
+/// out of it is dropped.
+pub fn relative_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selection() {
+        let selection = Selection::new(["/var/log/**", "**/.ssh/*"], ["**/*.gz"]).unwrap();
+
+        assert!(selection.is_match("/var/log/syslog"));
+        assert!(selection.is_match("/var/log/nginx/access.log"));
+        assert!(selection.is_match("/home/user/.ssh/id_ed25519"));
+        assert!(!selection.is_match("/var/log/syslog.2.gz"));
+        assert!(!selection.is_match("/var/lib/dpkg/status"));
+
+        // `*` doesn't cross separators, matching `filesystem-iter`.
+        assert!(!selection.is_match("/home/user/.ssh/keys/id_rsa"));
+
+        let everything = Selection::new::<_, _, &str>([], []).unwrap();
+        assert!(everything.is_match("/etc/shadow"));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let actual = relative_path(Path::new("/etc/passwd"));
+        assert_eq!(PathBuf::from("etc/passwd"), actual);
+
+        let actual = relative_path(Path::new("/../etc/./passwd"));
+        assert_eq!(PathBuf::from("etc/passwd"), actual);
+    }
+}
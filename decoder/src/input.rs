@@ -0,0 +1,123 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use ureq::http::header::AUTHORIZATION;
+
+/// Open a previously captured stream, where `-` means stdin.
+pub fn open_saved(path: &Path) -> io::Result<Box<dyn Read>> {
+    if path == Path::new("-") {
+        Ok(Box::new(BufReader::new(io::stdin().lock())))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// Pull a stream straight from a running agent, e.g.
+/// `http://host:9001/fs`. The response is decoded as it arrives, and
+/// optionally also written untouched to `tee`.
+///
+/// With the manifest of a `baseline`, the agent only sends what changed
+/// since. A `token` is sent as the bearer token, for agents that want one.
+pub fn open_url(
+    url: &str,
+    token: Option<&str>,
+    tee: Option<&Path>,
+    baseline: Option<&[u8]>,
+) -> anyhow::Result<Box<dyn Read>> {
+    let authorization = token.map(|token| format!("Bearer {token}"));
+    let response = match (baseline, authorization) {
+        (Some(baseline), Some(authorization)) => ureq::post(url)
+            .header(AUTHORIZATION, authorization)
+            .send(baseline)?,
+        (Some(baseline), None) => ureq::post(url).send(baseline)?,
+        (None, Some(authorization)) => {
+            ureq::get(url).header(AUTHORIZATION, authorization).call()?
+        }
+        (None, None) => ureq::get(url).call()?,
+    };
+    let reader = BufReader::new(response.into_body().into_reader());
+
+    match tee {
+        Some(path) => Ok(Box::new(Tee {
+            inner: reader,
+            copy: BufWriter::new(File::create(path)?),
+        })),
+        None => Ok(Box::new(reader)),
+    }
+}
+
+/// Copies everything read through it into `copy`.
+struct Tee<R, W> {
+    inner: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        if read == 0 {
+            self.copy.flush()?;
+        } else {
+            self.copy.write_all(&buf[..read])?;
+        }
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use agentfs::{auth::Auth, web_service};
+    use tokio::{net::TcpListener, runtime::Runtime};
+
+    use super::*;
+    use crate::{
+        extract::{Selection, extract, relative_path},
+        reader::Entries,
+    };
+
+    #[test]
+    fn test_live_agent() {
+        let source = tempfile::tempdir().unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let tee = tempfile::NamedTempFile::new().unwrap();
+
+        fs::create_dir(source.path().join("logs")).unwrap();
+        fs::write(source.path().join("logs/syslog"), "hello\n".repeat(10_000)).unwrap();
+        fs::write(source.path().join("notes.txt"), "not wanted").unwrap();
+
+        let runtime = Runtime::new().unwrap();
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let url = format!("http://{}/fs", listener.local_addr().unwrap());
+
+        runtime.spawn(web_service::serve(
+            listener,
+            [source.path().to_owned()],
+            Default::default(),
+            Auth::default(),
+        ));
+
+        let selection = Selection::new(["**/logs/*"], []).unwrap();
+        let input = open_url(&url, None, Some(tee.path()), None).unwrap();
+        extract(&mut Entries::new(input), output.path(), &selection).unwrap();
+
+        let extracted = output.path().join(relative_path(source.path()));
+        let syslog = fs::read_to_string(extracted.join("logs/syslog")).unwrap();
+        assert_eq!("hello\n".repeat(10_000), syslog);
+        assert!(!extracted.join("notes.txt").exists());
+
+        // The tee'd copy decodes the same way as the live stream.
+        let again = tempfile::tempdir().unwrap();
+        let input = open_saved(tee.path()).unwrap();
+        extract(&mut Entries::new(input), again.path(), &selection).unwrap();
+
+        let extracted = again.path().join(relative_path(source.path()));
+        assert!(extracted.join("logs/syslog").exists());
+    }
+}
@@ -0,0 +1,8 @@
+pub mod chunks;
+pub mod convert;
+pub mod decrypt;
+pub mod extract;
+pub mod input;
+pub mod manifest;
+pub mod reader;
+pub mod verify;
@@ -1,28 +1,266 @@
-use std::path::PathBuf;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
 
-use bytes::BytesMut;
-use clap::Parser;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, BufReader},
+use agentfs::encryption::Identity;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use decoder::{
+    convert::{ArchiveFormat, convert},
+    decrypt::decrypt,
+    extract::{Selection, extract},
+    input::{open_saved, open_url},
+    manifest::{ManifestFormat, manifest, read_manifest},
+    reader::Entries,
+    verify::verify,
 };
 
 #[derive(Debug, Parser)]
 struct Args {
-    /// Path to previously captured stream
+    /// Path to previously captured stream, `-` for stdin
+    #[clap(short, long, conflicts_with = "url")]
+    saved_stream: Option<PathBuf>,
+
+    /// Fetch the stream from a running agent, e.g. `http://host:9001/fs`
     #[clap(short, long)]
-    saved_stream: PathBuf,
+    url: Option<String>,
+
+    /// Bearer token for agents that want one
+    #[clap(long, env = "AGENT_TOKEN", hide_env_values = true, requires = "url")]
+    token: Option<String>,
+
+    /// Also save the raw stream fetched from `--url` to this path
+    #[clap(long, requires = "url")]
+    tee: Option<PathBuf>,
+
+    /// Manifest of a previous capture, for the agent to send only what changed
+    /// since when fetching from `--url`, and to fill in the rest of `manifest`
+    #[clap(long)]
+    baseline: Option<PathBuf>,
+
+    /// File holding the hex X25519 private key to decrypt an encrypted capture with
+    #[clap(long)]
+    identity: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Extract files from the capture onto disk
+    Extract {
+        /// Directory to write extracted files into
+        #[clap(short, long)]
+        output: PathBuf,
+
+        /// Only extract paths matching these globs (defaults to everything)
+        #[clap(short, long)]
+        include: Vec<String>,
+
+        /// Skip paths matching these globs
+        #[clap(short, long)]
+        exclude: Vec<String>,
+    },
+
+    /// Transcode the capture into a standard archive
+    Convert {
+        /// Archive format to write
+        #[clap(short, long, value_enum)]
+        format: ArchiveFormat,
+
+        /// Where to write the archive, `-` for stdout
+        #[clap(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+
+    /// List every captured entry along with its metadata and digests
+    Manifest {
+        /// Manifest format to write
+        #[clap(short, long, value_enum, default_value = "json")]
+        format: ManifestFormat,
+
+        /// Where to write the manifest, `-` for stdout
+        #[clap(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+
+    /// Check every file's contents against the digests the agent sent, and the
+    /// capture against its signature if it's signed
+    Verify {
+        /// Hex Ed25519 public key the capture must be signed by
+        #[clap(long)]
+        signer: Option<String>,
+    },
+
+    /// Show where to resume an interrupted capture from
+    Checkpoint,
+
+    /// Generate a key pair for agents to encrypt captures to, printing the
+    /// public key to pass to them
+    Keygen {
+        /// Where to write the private key, for `--identity`
+        #[clap(short, long)]
+        output: PathBuf,
+    },
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let file = File::open(args.saved_stream).await?;
-    let mut reader = BufReader::new(file);
-    let mut bytes = BytesMut::with_capacity(1024 * 8);
+    if let Command::Keygen { output } = &args.command {
+        return keygen(output);
+    }
+
+    let baseline = args.baseline.map(fs::read).transpose()?;
+    let identity = args
+        .identity
+        .map(|path| {
+            fs::read_to_string(&path)?
+                .parse::<Identity>()
+                .with_context(|| format!("{}: invalid identity", path.display()))
+        })
+        .transpose()?;
+
+    let reader = match (&args.saved_stream, &args.url) {
+        (Some(path), _) => open_saved(path)?,
+        (None, Some(url)) => open_url(
+            url,
+            args.token.as_deref(),
+            args.tee.as_deref(),
+            baseline.as_deref(),
+        )?,
+        (None, None) => anyhow::bail!("pass either `--saved-stream` or `--url`"),
+    };
+
+    let mut entries = Entries::new(decrypt(reader, identity.as_ref())?);
+
+    match args.command {
+        Command::Extract {
+            output,
+            include,
+            exclude,
+        } => extract(&mut entries, &output, &Selection::new(include, exclude)?)?,
+        Command::Convert { format, output } => {
+            for path in convert(&mut entries, open_output(&output)?, format)? {
+                eprintln!("padded with zeroes: {}", path.display());
+            }
+        }
+        Command::Manifest { format, output } => {
+            let baseline = baseline.as_deref().map(read_manifest).transpose()?;
+            manifest(&mut entries, open_output(&output)?, format, baseline)?
+        }
+        Command::Verify { signer } => {
+            let verification = verify(&mut entries)?;
+            let signature = entries.signature();
 
-    while reader.read_buf(&mut bytes).await? > 0 {}
+            for (path, algorithms) in &verification.mismatched {
+                let names: Vec<_> = algorithms.iter().map(|a| a.name()).collect();
+                eprintln!("mismatch: {} ({})", path.display(), names.join(", "));
+            }
+
+            report_damage(&entries);
+
+            println!(
+                "{} verified, {} mismatched, {} unverifiable, {} not collected",
+                verification.verified,
+                verification.mismatched.len(),
+                verification.unverifiable.len(),
+                verification.not_collected
+            );
+
+            match &signature {
+                Some(signature) if signature.valid => {
+                    println!("signed by {}", signature.signer);
+                    println!("parameters: {}", signature.parameters);
+
+                    if signature.start > 0 {
+                        println!(
+                            "resumed: only the capture from offset {} on is signed",
+                            signature.start
+                        );
+                    }
+                }
+                Some(signature) => eprintln!(
+                    "invalid signature from {}, the capture was altered or damaged",
+                    signature.signer
+                ),
+                None => println!("not signed"),
+            }
+
+            anyhow::ensure!(verification.is_ok(), "some files don't match their digests");
+            anyhow::ensure!(
+                signature.as_ref().is_none_or(|signature| signature.valid),
+                "the capture's signature doesn't hold up"
+            );
+
+            if let Some(signer) = signer {
+                anyhow::ensure!(
+                    signature.is_some_and(|signature| signature.signer == signer.to_lowercase()),
+                    "the capture isn't signed by {signer}"
+                );
+            }
+
+            return Ok(());
+        }
+        Command::Checkpoint => {
+            while entries.next_entry()?.is_some() {}
+
+            let Some(checkpoint) = entries.checkpoint() else {
+                anyhow::bail!("the capture has no checkpoints, so it can't be resumed");
+            };
+
+            println!(
+                "keep the first {} bytes, and append `?resume={}&from={}`",
+                checkpoint.offset, checkpoint.job, checkpoint.from
+            );
+        }
+        Command::Keygen { .. } => unreachable!("handled before reading any input"),
+    }
+
+    report_damage(&entries);
 
     Ok(())
 }
+
+/// Let the user know if anything had to be recovered from a damaged capture.
+fn report_damage<R: Read>(entries: &Entries<R>) {
+    for path in entries.partial_files() {
+        eprintln!("partial: {}", path.display());
+    }
+
+    for range in entries.damaged_ranges() {
+        eprintln!(
+            "unreadable: bytes {}..{} ({} bytes)",
+            range.start,
+            range.end,
+            range.end - range.start
+        );
+    }
+}
+
+/// Write a new private key to `output`, readable only by its owner, and
+/// print its public key.
+fn keygen(output: &Path) -> anyhow::Result<()> {
+    let identity = Identity::generate();
+
+    let mut file = File::options();
+    file.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+
+    writeln!(file.open(output)?, "{identity}")?;
+    println!("{}", identity.recipient());
+
+    Ok(())
+}
+
+fn open_output(path: &Path) -> io::Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        Ok(Box::new(BufWriter::new(io::stdout().lock())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    iter,
+};
+
+use agentfs::{digest::DigestAlgorithm, mode::CollectionMode, stream::path_to_bytes};
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::reader::{Entries, EntryKind};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ManifestFormat {
+    Json,
+    Csv,
+}
+
+/// One line of the manifest. Digests are lowercase hex, and empty for
+/// directories, for files that were cut off, and for algorithms the agent
+/// wasn't asked to use.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestRow {
+    pub path: String,
+    /// The path's raw bytes in hex, if it isn't valid UTF-8 and `path` had
+    /// to stand replacement characters in for some. Empty otherwise.
+    #[serde(default)]
+    pub path_hex: String,
+    /// `file`, `directory`, or `deleted` for a file the baseline had that's
+    /// gone.
+    pub kind: String,
+    pub size: u64,
+    pub mode: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    /// Missing from manifests written before it was added.
+    #[serde(default)]
+    pub mtime_nsec: u32,
+    #[serde(default)]
+    pub inode: u64,
+    pub partial: bool,
+    /// `metadata`, `hash`, `full`, `known` or `unchanged`, and empty for
+    /// directories.
+    pub collection: String,
+    /// Whether the agent read the file without updating its access time.
+    pub atime_preserved: bool,
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+    pub sha3_256: String,
+    pub blake3: String,
+}
+
+/// Write the path, metadata and digests of every captured entry, without
+/// decompressing any file contents.
+///
+/// For a collection made against a `baseline`, the manifest of that
+/// baseline can be given to combine the two into the full picture.
+/// Unchanged files take their digests from the baseline, deleted ones are
+/// left out, and the baseline's other entries are listed after the
+/// capture's own.
+pub fn manifest<R: Read>(
+    entries: &mut Entries<R>,
+    output: impl Write,
+    format: ManifestFormat,
+    baseline: Option<Vec<ManifestRow>>,
+) -> anyhow::Result<()> {
+    let rows = iter::from_fn(|| next_row(entries).transpose());
+
+    match baseline {
+        Some(baseline) => write_rows(Combined::new(rows, baseline), output, format),
+        None => write_rows(rows, output, format),
+    }
+}
+
+/// Read a manifest written by `manifest`, as JSON or CSV.
+pub fn read_manifest(input: &[u8]) -> anyhow::Result<Vec<ManifestRow>> {
+    match input.trim_ascii_start().first() {
+        Some(b'[') => serde_json::from_slice(input).context("invalid JSON manifest"),
+        _ => csv::Reader::from_reader(input)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .context("invalid CSV manifest"),
+    }
+}
+
+fn write_rows(
+    rows: impl Iterator<Item = anyhow::Result<ManifestRow>>,
+    mut output: impl Write,
+    format: ManifestFormat,
+) -> anyhow::Result<()> {
+    match format {
+        ManifestFormat::Json => {
+            output.write_all(b"[")?;
+
+            for (index, row) in rows.enumerate() {
+                output.write_all(if index == 0 { b"\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut output, &row?)?;
+            }
+
+            output.write_all(b"\n]\n")?;
+            output.flush()?;
+        }
+        ManifestFormat::Csv => {
+            let mut csv = csv::Writer::from_writer(output);
+
+            for row in rows {
+                csv.serialize(row?)?;
+            }
+
+            csv.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn next_row<R: Read>(entries: &mut Entries<R>) -> anyhow::Result<Option<ManifestRow>> {
+    let Some(entry) = entries.next_entry()? else {
+        return Ok(None);
+    };
+
+    let (kind, size, mode, atime_preserved, digests) = match entry.kind {
+        EntryKind::File {
+            len,
+            atime_preserved,
+            mode,
+        } => ("file", len, Some(mode), atime_preserved, entries.finish()?),
+        EntryKind::Directory => ("directory", 0, None, false, None),
+        EntryKind::Deleted => ("deleted", 0, None, false, None),
+    };
+
+    let digests = digests.as_ref();
+    let digest = |algorithm| {
+        digests
+            .and_then(|digests| digests.get(algorithm))
+            .map(hex)
+            .unwrap_or_default()
+    };
+
+    let bytes = path_to_bytes(&entry.path);
+    let path_hex = match str::from_utf8(&bytes) {
+        Ok(_) => String::new(),
+        Err(_) => hex(&bytes),
+    };
+
+    Ok(Some(ManifestRow {
+        path: entry.path.to_string_lossy().into_owned(),
+        path_hex,
+        kind: kind.to_owned(),
+        size,
+        mode: format!("{:04o}", entry.metadata.mode),
+        uid: entry.metadata.uid,
+        gid: entry.metadata.gid,
+        mtime: entry.metadata.mtime,
+        mtime_nsec: entry.metadata.mtime_nsec,
+        inode: entry.metadata.inode,
+        partial: mode.is_some_and(CollectionMode::has_footer) && digests.is_none(),
+        collection: mode.map_or("", CollectionMode::name).to_owned(),
+        atime_preserved,
+        md5: digest(DigestAlgorithm::Md5),
+        sha1: digest(DigestAlgorithm::Sha1),
+        sha256: digest(DigestAlgorithm::Sha256),
+        sha3_256: digest(DigestAlgorithm::Sha3_256),
+        blake3: digest(DigestAlgorithm::Blake3),
+    }))
+}
+
+/// The rows of a capture made against a baseline, merged with the
+/// baseline's.
+struct Combined<I> {
+    delta: I,
+    delta_done: bool,
+    /// Taken as the capture mentions them, leaving the rest to list after.
+    baseline: Vec<Option<ManifestRow>>,
+    /// By `path` and `path_hex` together, which tell every path apart.
+    by_path: HashMap<(String, String), usize>,
+    leftover: usize,
+}
+
+impl<I> Combined<I> {
+    fn new(delta: I, baseline: Vec<ManifestRow>) -> Self {
+        let by_path = baseline
+            .iter()
+            .enumerate()
+            .map(|(index, row)| ((row.path.clone(), row.path_hex.clone()), index))
+            .collect();
+
+        Self {
+            delta,
+            delta_done: false,
+            baseline: baseline.into_iter().map(Some).collect(),
+            by_path,
+            leftover: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = anyhow::Result<ManifestRow>>> Iterator for Combined<I> {
+    type Item = anyhow::Result<ManifestRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.delta_done {
+            let row = match self.delta.next() {
+                Some(Ok(row)) => row,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    self.delta_done = true;
+                    break;
+                }
+            };
+
+            let base = self
+                .by_path
+                .get(&(row.path.clone(), row.path_hex.clone()))
+                .and_then(|&index| self.baseline[index].take());
+
+            match base {
+                _ if row.kind == "deleted" => continue,
+                // The contents are as they were, but the rest of the
+                // metadata may not be.
+                Some(base) if row.collection == CollectionMode::Unchanged.name() => {
+                    return Some(Ok(ManifestRow {
+                        path: row.path,
+                        path_hex: row.path_hex,
+                        kind: row.kind,
+                        size: row.size,
+                        mode: row.mode,
+                        uid: row.uid,
+                        gid: row.gid,
+                        mtime: row.mtime,
+                        mtime_nsec: row.mtime_nsec,
+                        inode: row.inode,
+                        ..base
+                    }));
+                }
+                _ => return Some(Ok(row)),
+            }
+        }
+
+        while self.leftover < self.baseline.len() {
+            self.leftover += 1;
+
+            if let Some(row) = self.baseline[self.leftover - 1].take() {
+                return Some(Ok(row));
+            }
+        }
+
+        None
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use agentfs::{
+        baseline::Baseline,
+        stream::{StreamOptions, build_stream},
+    };
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    fn collect(root: &Path, options: StreamOptions) -> Vec<u8> {
+        let stream: Vec<Bytes> = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(build_stream([root.to_owned()].into_iter(), options).try_collect())
+            .unwrap();
+
+        stream.concat()
+    }
+
+    /// The manifest of a stream, as written out and read back in.
+    fn rows(stream: &[u8], baseline: Option<Vec<ManifestRow>>) -> Vec<ManifestRow> {
+        let mut output = Vec::new();
+        let mut entries = Entries::new(stream);
+        manifest(&mut entries, &mut output, ManifestFormat::Csv, baseline).unwrap();
+
+        let mut rows = read_manifest(&output).unwrap();
+        rows.sort_by(|a, b| a.path.cmp(&b.path));
+
+        rows
+    }
+
+    #[test]
+    fn test_incremental() {
+        let root = tempfile::tempdir().unwrap();
+        let path = |name| root.path().join(name);
+
+        for name in ["same", "changed", "touched", "gone"] {
+            fs::write(path(name), format!("{name} contents")).unwrap();
+        }
+
+        let first = rows(&collect(root.path(), StreamOptions::default()), None);
+        let mut json = Vec::new();
+        write_rows(
+            first.iter().cloned().map(Ok),
+            &mut json,
+            ManifestFormat::Json,
+        )
+        .unwrap();
+
+        fs::write(path("changed"), "different contents").unwrap();
+        fs::remove_file(path("gone")).unwrap();
+        fs::write(path("new"), "new contents").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path("touched"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000))
+            .unwrap();
+
+        let options = StreamOptions {
+            baseline: Some(Arc::new(Baseline::parse(&json).unwrap())),
+            ..Default::default()
+        };
+        let delta = collect(root.path(), options);
+
+        let kinds: Vec<_> = rows(&delta, None)
+            .into_iter()
+            .filter(|row| row.kind != "directory")
+            .map(|row| {
+                let name = Path::new(&row.path).strip_prefix(root.path()).unwrap();
+                (name.display().to_string(), row.kind, row.collection)
+            })
+            .collect();
+        let expected = [
+            ("changed", "file", "full"),
+            ("gone", "deleted", ""),
+            ("new", "file", "full"),
+            ("same", "file", "unchanged"),
+            // Rehashed, since its modification time changed.
+            ("touched", "file", "unchanged"),
+        ]
+        .map(|(name, kind, collection)| (name.to_owned(), kind.to_owned(), collection.to_owned()));
+        assert_eq!(expected.as_slice(), kinds);
+
+        // The baseline and its delta add up to what a full collection sees.
+        let full = rows(&collect(root.path(), StreamOptions::default()), None);
+        assert_eq!(full, rows(&delta, Some(first)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let locked = root.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::write(locked.join("inside"), "inside contents").unwrap();
+        fs::write(root.path().join("gone"), "gone contents").unwrap();
+
+        let first = rows(&collect(root.path(), StreamOptions::default()), None);
+        let mut json = Vec::new();
+        write_rows(first.into_iter().map(Ok), &mut json, ManifestFormat::Json).unwrap();
+
+        fs::remove_file(root.path().join("gone")).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        let readable = fs::read_dir(&locked).is_ok();
+
+        let options = StreamOptions {
+            baseline: Some(Arc::new(Baseline::parse(&json).unwrap())),
+            ..Default::default()
+        };
+        let delta = collect(root.path(), options);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        if readable {
+            // Permissions don't stop root, so there's nothing to test.
+            return;
+        }
+
+        let deleted: Vec<_> = rows(&delta, None)
+            .into_iter()
+            .filter(|row| row.kind == "deleted")
+            .map(|row| row.path)
+            .collect();
+        let gone = root.path().join("gone").display().to_string();
+        assert_eq!(vec![gone], deleted);
+    }
+}
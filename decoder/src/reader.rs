@@ -0,0 +1,1158 @@
+use std::{
+    io::{self, BufReader, Read},
+    mem,
+    ops::Range,
+    path::PathBuf,
+};
+
+use agentfs::{
+    codec::Codec,
+    digest::{Digest, DigestAlgorithm},
+    mode::CollectionMode,
+    signing,
+    stream::{
+        ArchivedMessage, EntryMetadata, FRAME_HEADER_LEN, FRAME_MAGIC, MAX_FRAME_LEN, ResumePoint,
+        path_from_bytes,
+    },
+};
+use lz4_flex::frame::FrameDecoder;
+use rkyv::{rancor, util::AlignedVec};
+use thiserror::Error;
+
+use crate::{chunks::ChunkStore, manifest::hex};
+
+/// How much to pull from the input at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Why a capture couldn't be decoded.
+///
+/// Captures come from hosts that may be compromised, so nothing in them is
+/// trusted. Damage is recovered from, whether a checksum catches it or the
+/// message inside an intact frame is malformed; these are the problems that
+/// can't be.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("failed to read the capture")]
+    Io(#[from] io::Error),
+
+    #[error("not currently reading a file")]
+    NotInFile,
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+/// Splits a captured stream back into the messages the agent emitted.
+///
+/// Damaged or missing data is stepped over by scanning ahead for the next
+/// frame that checks out, and the skipped byte ranges are recorded.
+struct StreamReader<R> {
+    inner: R,
+    /// Input that has been read but not yet consumed, from `start` onwards.
+    pending: Vec<u8>,
+    start: usize,
+    /// Offset into the stream of `pending[start]`.
+    position: u64,
+    eof: bool,
+    buffer: AlignedVec,
+    /// Whether `buffer` holds a message that's been validated.
+    valid: bool,
+    /// Whether data had to be skipped to reach the current message.
+    resynced: bool,
+    damaged: Vec<Range<u64>>,
+    /// Every byte consumed since the latest `StreamHeader`, to check a
+    /// signed stream against.
+    chain: blake3::Hasher,
+    /// Where the latest `StreamHeader` started, which is where a resumed
+    /// capture's last attempt begins.
+    stream_start: u64,
+    /// What `chain` was just before the most recent `Trailer`.
+    chain_before_trailer: [u8; 32],
+}
+
+impl<R: Read> StreamReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            start: 0,
+            position: 0,
+            eof: false,
+            buffer: AlignedVec::new(),
+            valid: false,
+            resynced: false,
+            damaged: Vec::new(),
+            chain: blake3::Hasher::new(),
+            stream_start: 0,
+            chain_before_trailer: [0; 32],
+        }
+    }
+
+    /// Read the next intact message, returning `None` at the end of the
+    /// stream.
+    fn next_message(&mut self) -> Result<Option<&ArchivedMessage>, DecodeError> {
+        let mut damage_start = None;
+
+        self.resynced = false;
+
+        loop {
+            let frame_start = self.position;
+
+            if self.try_frame()? {
+                if let Some(start) = damage_start {
+                    self.damaged.push(start..frame_start);
+                    self.resynced = true;
+                }
+
+                return Ok(Some(self.current()));
+            }
+
+            if self.fill(1)? == 0 {
+                if let Some(start) = damage_start {
+                    self.damaged.push(start..frame_start);
+                }
+
+                return Ok(None);
+            }
+
+            damage_start.get_or_insert(frame_start);
+            self.skip_to_magic()?;
+        }
+    }
+
+    /// The message most recently returned by `next_message`.
+    fn current(&self) -> &ArchivedMessage {
+        assert!(self.valid, "no message has been read");
+
+        // SAFETY: `try_frame` only sets `valid` once `access` has checked the
+        // buffer, and clears it before the buffer changes again.
+        unsafe { rkyv::access_unchecked::<ArchivedMessage>(&self.buffer) }
+    }
+
+    /// Load the frame at the current position if it's intact and holds a
+    /// well-formed message.
+    fn try_frame(&mut self) -> Result<bool, DecodeError> {
+        if self.fill(FRAME_HEADER_LEN)? < FRAME_HEADER_LEN {
+            return Ok(false);
+        }
+
+        let header = &self.pending[self.start..][..FRAME_HEADER_LEN];
+        let (magic, rest) = header.split_at(FRAME_MAGIC.len());
+        let (len, crc) = rest.split_at(size_of::<u32>());
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(crc.try_into().unwrap());
+
+        if magic != FRAME_MAGIC || len > MAX_FRAME_LEN {
+            return Ok(false);
+        }
+
+        if self.fill(FRAME_HEADER_LEN + len)? < FRAME_HEADER_LEN + len {
+            return Ok(false);
+        }
+
+        let payload = &self.pending[self.start + FRAME_HEADER_LEN..][..len];
+
+        if crc32fast::hash(payload) != crc {
+            return Ok(false);
+        }
+
+        self.valid = false;
+        self.buffer.clear();
+        self.buffer.extend_from_slice(payload);
+
+        match access(&self.buffer) {
+            // Written that way on purpose, or damaged in a way the checksum
+            // missed, either way it's skipped like any other damage.
+            Err(_) => return Ok(false),
+            // Each attempt at a resumed collection is signed on its own.
+            Ok(ArchivedMessage::StreamHeader { .. }) => {
+                self.chain = blake3::Hasher::new();
+                self.stream_start = self.position;
+            }
+            Ok(ArchivedMessage::Trailer { .. }) => {
+                self.chain_before_trailer = *self.chain.finalize().as_bytes();
+            }
+            Ok(_) => {}
+        }
+
+        self.valid = true;
+        self.consume(FRAME_HEADER_LEN + len);
+
+        Ok(true)
+    }
+
+    /// Step past the current position to the next place a frame might start.
+    fn skip_to_magic(&mut self) -> io::Result<()> {
+        self.consume(1);
+
+        loop {
+            let available = self.fill(FRAME_MAGIC.len())?;
+
+            if available < FRAME_MAGIC.len() {
+                self.consume(available);
+                return Ok(());
+            }
+
+            let window = &self.pending[self.start..];
+
+            match window
+                .windows(FRAME_MAGIC.len())
+                .position(|w| w == FRAME_MAGIC)
+            {
+                Some(offset) => {
+                    self.consume(offset);
+                    return Ok(());
+                }
+                // Keep a possible partial magic at the end of the window.
+                None => self.consume(available - (FRAME_MAGIC.len() - 1)),
+            }
+        }
+    }
+
+    /// Make at least `len` unconsumed bytes available, unless the input runs
+    /// out first. Returns how many are available.
+    fn fill(&mut self, len: usize) -> io::Result<usize> {
+        while self.pending.len() - self.start < len && !self.eof {
+            if self.start > 0 {
+                self.pending.drain(..self.start);
+                self.start = 0;
+            }
+
+            let filled = self.pending.len();
+            self.pending.resize(filled + len.max(READ_SIZE), 0);
+
+            let read = loop {
+                match self.inner.read(&mut self.pending[filled..]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    result => break result,
+                }
+            };
+
+            let read = read.inspect_err(|_| self.pending.truncate(filled))?;
+            self.pending.truncate(filled + read);
+            self.eof = read == 0;
+        }
+
+        Ok(self.pending.len() - self.start)
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.chain
+            .update(&self.pending[self.start..self.start + len]);
+        self.start += len;
+        self.position += len as u64;
+    }
+}
+
+/// Check that `bytes` hold a well-formed message before handing it out.
+fn access(bytes: &[u8]) -> Result<&ArchivedMessage, rancor::Error> {
+    rkyv::access::<ArchivedMessage, rancor::Error>(bytes)
+}
+
+/// Digests sent by the agent once a file's contents have been streamed.
+#[derive(Debug, Clone, Default)]
+pub struct FileDigests(pub Vec<Digest>);
+
+impl FileDigests {
+    pub fn get(&self, algorithm: DigestAlgorithm) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|digest| digest.algorithm == algorithm)
+            .map(|digest| digest.value.as_slice())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File {
+        len: u64,
+        /// Whether the agent read the file without updating its access
+        /// time.
+        atime_preserved: bool,
+        /// Whether the contents, or only their digests, were captured.
+        mode: CollectionMode,
+    },
+    Directory,
+    /// A file in the baseline the collection was made against that's no
+    /// longer there. Its metadata is all zeroes.
+    Deleted,
+}
+
+/// How far a resumable stream got, as of its last checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub job: String,
+    /// The last entry received in full, which is where to resume from.
+    pub from: ResumePoint,
+    /// How much of the stream to keep, with the resumed stream appended.
+    pub offset: u64,
+}
+
+/// The `Trailer` of a signed stream, checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// The agent's Ed25519 public key, as hex.
+    pub signer: String,
+    /// What the collection was asked for, as JSON.
+    pub parameters: String,
+    /// Whether the signature is good, over exactly the stream before it,
+    /// with nothing after.
+    pub valid: bool,
+    /// Where the signed stream starts. Anything before it is an earlier,
+    /// interrupted attempt at a resumed collection, which it doesn't cover.
+    pub start: u64,
+}
+
+#[derive(Debug)]
+struct SignatureCheck {
+    signer: String,
+    parameters: String,
+    valid: bool,
+    start: u64,
+    /// Where the `Trailer` ended.
+    end: u64,
+}
+
+/// A captured file or directory, without its contents.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub path: PathBuf,
+    pub metadata: EntryMetadata,
+}
+
+/// Walks the files and directories of a captured stream.
+///
+/// After `next_entry` returns a file, its contents can be read through
+/// `contents` and its digests through `finish`. Anything left unread is
+/// skipped over, without being decompressed, by the next call to
+/// `next_entry`.
+///
+/// A file whose contents were cut off by damage to the stream is reported as
+/// partial, and keeps whatever could be decompressed before the damage.
+pub struct Entries<R> {
+    frames: StreamReader<R>,
+    body: Option<BodyState>,
+    /// The current message is an entry that cut the previous file short.
+    peeked: bool,
+    partial: Vec<PathBuf>,
+    /// What the agent said it would put in every footer, once its
+    /// `StreamHeader` has been read.
+    digest_algorithms: Option<Vec<DigestAlgorithm>>,
+    chunks: ChunkStore,
+    /// The job the stream belongs to, if it's resumable.
+    job: Option<String>,
+    checkpoint: Option<Checkpoint>,
+    signature: Option<SignatureCheck>,
+}
+
+/// Where the current file's contents are up to.
+#[derive(Debug)]
+struct BodyState {
+    path: PathBuf,
+    codec: Codec,
+    header_only: bool,
+    offset: usize,
+    in_body: bool,
+    /// The decompressed contents of the current `Chunk` or `ChunkRef`.
+    chunk: Option<Vec<u8>>,
+    done: bool,
+    footer: Option<FileDigests>,
+}
+
+/// What a message means to the file currently being read.
+enum Next {
+    Body,
+    /// A chunk's contents, or `None` if they're missing or damaged.
+    Chunk(Option<Vec<u8>>),
+    Footer(FileDigests),
+    Entry,
+    End,
+}
+
+impl<R: Read> Entries<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            frames: StreamReader::new(inner),
+            body: None,
+            peeked: false,
+            partial: Vec::new(),
+            digest_algorithms: None,
+            chunks: ChunkStore::default(),
+            job: None,
+            checkpoint: None,
+            signature: None,
+        }
+    }
+
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, DecodeError> {
+        if self.body.is_some() {
+            self.finish()?;
+        }
+
+        loop {
+            let mut codec = Codec::Store;
+            let message = if mem::take(&mut self.peeked) {
+                Some(self.frames.current())
+            } else {
+                self.frames.next_message()?
+            };
+
+            let entry = match message {
+                None => return Ok(None),
+                Some(ArchivedMessage::FileHeader {
+                    path,
+                    len,
+                    codec: file_codec,
+                    metadata,
+                    atime_preserved,
+                    mode,
+                }) => {
+                    codec = (*file_codec).into();
+
+                    Entry {
+                        kind: EntryKind::File {
+                            len: len.to_native(),
+                            atime_preserved: *atime_preserved,
+                            mode: (*mode).into(),
+                        },
+                        path: path_from_bytes(path),
+                        metadata: metadata.into(),
+                    }
+                }
+                Some(ArchivedMessage::Directory { path, metadata }) => Entry {
+                    kind: EntryKind::Directory,
+                    path: path_from_bytes(path),
+                    metadata: metadata.into(),
+                },
+                Some(ArchivedMessage::Deleted { path }) => Entry {
+                    kind: EntryKind::Deleted,
+                    path: path_from_bytes(path),
+                    metadata: EntryMetadata::default(),
+                },
+                Some(ArchivedMessage::StreamHeader { digests, job, .. }) => {
+                    self.digest_algorithms = Some(digests.iter().map(|&a| a.into()).collect());
+                    self.job = Some(job.to_string()).filter(|job| !job.is_empty());
+                    continue;
+                }
+                Some(ArchivedMessage::Checkpoint { path, deleted }) => {
+                    if let Some(job) = &self.job {
+                        self.checkpoint = Some(Checkpoint {
+                            job: job.clone(),
+                            from: ResumePoint {
+                                path: path_from_bytes(path),
+                                deleted: *deleted,
+                            },
+                            offset: self.frames.position,
+                        });
+                    }
+
+                    continue;
+                }
+                Some(ArchivedMessage::Trailer {
+                    parameters,
+                    chain,
+                    signer,
+                    signature,
+                }) => {
+                    let (parameters, chain, signer) = (parameters.to_string(), **chain, **signer);
+                    let valid = signing::verify(&signer, &chain, &parameters, signature)
+                        && chain == self.frames.chain_before_trailer;
+
+                    self.signature = Some(SignatureCheck {
+                        signer: hex(&signer),
+                        parameters,
+                        valid,
+                        start: self.frames.stream_start,
+                        end: self.frames.position,
+                    });
+
+                    continue;
+                }
+                // Contents whose header was lost to damage can't be placed,
+                // but a chunk may still be referred to by a later file.
+                Some(ArchivedMessage::Chunk { hash, codec, data }) => {
+                    self.chunks.insert(*hash, (*codec).into(), data)?;
+                    continue;
+                }
+                Some(
+                    ArchivedMessage::FileBody { .. }
+                    | ArchivedMessage::FileFooter { .. }
+                    | ArchivedMessage::ChunkRef { .. },
+                ) => {
+                    continue;
+                }
+            };
+
+            if let EntryKind::File { mode, .. } = entry.kind {
+                // A file captured as metadata alone, or unchanged since the
+                // baseline, has nothing after its header.
+                let header_only = !mode.has_footer();
+
+                self.body = Some(BodyState {
+                    path: entry.path.clone(),
+                    codec,
+                    header_only,
+                    offset: 0,
+                    in_body: false,
+                    chunk: None,
+                    done: header_only,
+                    footer: None,
+                });
+            }
+
+            return Ok(Some(entry));
+        }
+    }
+
+    /// The decompressed contents of the current file.
+    ///
+    /// If the file was cut off, this ends early rather than failing.
+    pub fn contents(&mut self) -> FileContents<'_, R> {
+        let codec = self.body.as_ref().map_or(Codec::Store, |body| body.codec);
+        let contents = Contents { entries: self };
+
+        let decoder = match codec {
+            Codec::Store => Decoder::Store(contents),
+            Codec::Lz4 => Decoder::Lz4(FrameDecoder::new(contents)),
+            Codec::Zstd => Decoder::Zstd(
+                zstd::Decoder::new(contents).expect("failed to allocate a zstd context"),
+            ),
+        };
+
+        FileContents { decoder }
+    }
+
+    /// Skip whatever is left of the current file's contents, and return the
+    /// digests the agent calculated for it. A file that was cut off, or
+    /// captured as metadata alone or unchanged, has no digests.
+    pub fn finish(&mut self) -> Result<Option<FileDigests>, DecodeError> {
+        loop {
+            match &self.body {
+                Some(body) if body.done => {
+                    let body = self.body.take().unwrap();
+
+                    if body.footer.is_none() && !body.header_only {
+                        self.partial.push(body.path);
+                    }
+
+                    return Ok(body.footer);
+                }
+                Some(_) => self.advance(false)?,
+                None => return Err(DecodeError::NotInFile),
+            }
+        }
+    }
+
+    /// The digests the agent calculates for every file, or `None` if the
+    /// stream's header hasn't been seen.
+    pub fn digest_algorithms(&self) -> Option<&[DigestAlgorithm]> {
+        self.digest_algorithms.as_deref()
+    }
+
+    /// Whether the current file's contents were cut off.
+    pub fn is_partial(&self) -> bool {
+        self.body
+            .as_ref()
+            .is_some_and(|body| body.done && body.footer.is_none() && !body.header_only)
+    }
+
+    /// The last checkpoint read, if the stream is resumable.
+    pub fn checkpoint(&self) -> Option<&Checkpoint> {
+        self.checkpoint.as_ref()
+    }
+
+    /// Who signed the stream, and whether the signature holds up. Only
+    /// meaningful once every entry has been read, since anything after the
+    /// `Trailer` invalidates it. `None` if the stream wasn't signed.
+    pub fn signature(&self) -> Option<Signature> {
+        self.signature.as_ref().map(|check| Signature {
+            signer: check.signer.clone(),
+            parameters: check.parameters.clone(),
+            valid: check.valid && check.end == self.frames.position,
+            start: check.start,
+        })
+    }
+
+    /// Files that were cut off by damage to the stream.
+    pub fn partial_files(&self) -> &[PathBuf] {
+        &self.partial
+    }
+
+    /// Byte ranges of the stream that couldn't be read.
+    pub fn damaged_ranges(&self) -> &[Range<u64>] {
+        &self.frames.damaged
+    }
+
+    /// Move on to the next message of the current file. Chunks are only
+    /// decompressed if their contents are `wanted`.
+    fn advance(&mut self, wanted: bool) -> Result<(), DecodeError> {
+        let next = match self.frames.next_message()? {
+            Some(ArchivedMessage::FileBody { .. }) => Next::Body,
+            Some(ArchivedMessage::Chunk { hash, codec, data }) => {
+                let contents = self.chunks.insert(*hash, (*codec).into(), data)?;
+
+                match wanted {
+                    true => Next::Chunk(contents),
+                    false => Next::Chunk(contents.map(|_| Vec::new())),
+                }
+            }
+            Some(ArchivedMessage::ChunkRef { hash }) => match wanted {
+                true => Next::Chunk(self.chunks.get(hash)?),
+                false => Next::Chunk(self.chunks.contains(hash).then(Vec::new)),
+            },
+            Some(ArchivedMessage::FileFooter { digests }) => {
+                Next::Footer(FileDigests(digests.iter().map(Digest::from).collect()))
+            }
+            Some(_) => Next::Entry,
+            None => Next::End,
+        };
+
+        let body = self
+            .body
+            .as_mut()
+            .expect("only called while reading a file");
+
+        body.offset = 0;
+        body.in_body = false;
+        body.chunk = None;
+
+        // Anything after a gap in the stream can't be trusted to belong to
+        // this file, and the compressed data can't continue across it anyway.
+        if self.frames.resynced {
+            body.done = true;
+            self.peeked = matches!(next, Next::Entry);
+
+            return Ok(());
+        }
+
+        match next {
+            Next::Body => body.in_body = true,
+            Next::Chunk(Some(contents)) => body.chunk = Some(contents),
+            // What's missing can't be put back, so the file ends here.
+            Next::Chunk(None) => body.done = true,
+            Next::Footer(footer) => {
+                body.footer = Some(footer);
+                body.done = true;
+            }
+            Next::Entry => {
+                body.done = true;
+                self.peeked = true;
+            }
+            Next::End => body.done = true,
+        }
+
+        Ok(())
+    }
+}
+
+/// The decompressed contents of the current file.
+pub struct FileContents<'a, R: Read> {
+    decoder: Decoder<'a, R>,
+}
+
+enum Decoder<'a, R: Read> {
+    Store(Contents<'a, R>),
+    Lz4(FrameDecoder<Contents<'a, R>>),
+    Zstd(zstd::Decoder<'static, BufReader<Contents<'a, R>>>),
+}
+
+impl<R: Read> Read for FileContents<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (result, contents) = match &mut self.decoder {
+            Decoder::Store(contents) => (contents.read(buf), &*contents),
+            Decoder::Lz4(decoder) => (decoder.read(buf), decoder.get_ref()),
+            Decoder::Zstd(decoder) => (decoder.read(buf), decoder.get_ref().get_ref()),
+        };
+
+        match result {
+            // The compressed data was cut off part way, so whatever came
+            // before it is all there is.
+            Err(_) if contents.entries.is_partial() => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// The still compressed contents of the current file, as one contiguous byte
+/// stream. Reading stops at the file's `FileFooter`.
+///
+/// A deduplicated file's chunks are each compressed on their own, so they're
+/// decompressed here instead, and the file's codec is `Store`.
+struct Contents<'a, R> {
+    entries: &'a mut Entries<R>,
+}
+
+impl<R: Read> Read for Contents<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(body) = &mut self.entries.body else {
+                return Ok(0);
+            };
+
+            if body.done {
+                return Ok(0);
+            }
+
+            let data: &[u8] = if body.in_body {
+                let ArchivedMessage::FileBody { data } = self.entries.frames.current() else {
+                    unreachable!("`in_body` is only set for body messages");
+                };
+
+                data
+            } else {
+                body.chunk.as_deref().unwrap_or_default()
+            };
+
+            let remaining = &data[body.offset..];
+
+            if !remaining.is_empty() {
+                let len = remaining.len().min(buf.len());
+                buf[..len].copy_from_slice(&remaining[..len]);
+                body.offset += len;
+
+                return Ok(len);
+            }
+
+            self.entries.advance(true)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use std::{fs, path::Path, sync::Arc};
+
+    use agentfs::{
+        codec::{Compression, Encoder},
+        digest::Hashers,
+        known::KnownHashes,
+        mode::ModeRules,
+        stream::{Message, StreamOptions, build_stream, frame},
+    };
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use lz4_flex::frame::FrameEncoder;
+    use rkyv::ser::allocator::Arena;
+
+    use super::*;
+
+    const CHUNK: usize = 64 * 1024;
+
+    /// Frames for a file, the same way the agent sends them, along with where
+    /// each frame starts.
+    fn file_frames(path: &str, data: &[u8], stream: &mut Vec<u8>) -> Vec<usize> {
+        let mut arena = Arena::new();
+        let mut starts = Vec::new();
+        let mut push = |message: Message, stream: &mut Vec<u8>| {
+            starts.push(stream.len());
+            stream.extend_from_slice(&frame(&message, &mut arena));
+        };
+
+        let header = Message::FileHeader {
+            path: path.into(),
+            len: data.len() as u64,
+            codec: Codec::Lz4,
+            metadata: EntryMetadata::default(),
+            atime_preserved: true,
+            mode: CollectionMode::Full,
+        };
+        push(header, stream);
+
+        let mut encoder = FrameEncoder::new(Vec::new());
+
+        for chunk in data.chunks(CHUNK) {
+            encoder.write_all(chunk).unwrap();
+            let data = mem::take(encoder.get_mut()).into();
+            push(Message::FileBody { data }, stream);
+        }
+
+        let data = encoder.finish().unwrap().into();
+        push(Message::FileBody { data }, stream);
+
+        let footer = Message::FileFooter {
+            digests: Vec::new(),
+        };
+        push(footer, stream);
+
+        starts
+    }
+
+    /// Incompressible enough that every chunk becomes its own block.
+    fn data(seed: u8, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u32).wrapping_mul(2654435761).to_le_bytes()[3] ^ seed)
+            .collect()
+    }
+
+    fn read_file<R: Read>(entries: &mut Entries<R>) -> (PathBuf, Vec<u8>, Option<FileDigests>) {
+        let entry = entries.next_entry().unwrap().unwrap();
+        let mut contents = Vec::new();
+        entries.contents().read_to_end(&mut contents).unwrap();
+
+        (entry.path, contents, entries.finish().unwrap())
+    }
+
+    #[test]
+    fn test_recovery() {
+        let (a, b, c) = (data(1, 100), data(2, 3 * CHUNK), data(3, CHUNK + 10));
+        let mut stream = Vec::new();
+
+        file_frames("/a", &a, &mut stream);
+        let b_frames = file_frames("/b", &b, &mut stream);
+        let c_frames = file_frames("/c", &c, &mut stream);
+
+        // The encoder holds on to each chunk until the next one arrives, so
+        // the first block is in the second body. Damage the third body of b,
+        // and lose the end of c.
+        let corrupt = b_frames[3] + FRAME_HEADER_LEN + 100;
+        stream[corrupt] ^= 0xff;
+        let cut = c_frames[3] + 10;
+        stream.truncate(cut);
+
+        let mut entries = Entries::new(stream.as_slice());
+
+        let (path, contents, digests) = read_file(&mut entries);
+        assert_eq!(PathBuf::from("/a"), path);
+        assert_eq!(a, contents);
+        assert!(digests.is_some());
+
+        // Only the first chunk of b survived.
+        let (path, contents, digests) = read_file(&mut entries);
+        assert_eq!(PathBuf::from("/b"), path);
+        assert_eq!(&b[..CHUNK], contents);
+        assert!(digests.is_none());
+
+        let (path, contents, digests) = read_file(&mut entries);
+        assert_eq!(PathBuf::from("/c"), path);
+        assert_eq!(&c[..CHUNK], contents);
+        assert!(digests.is_none());
+
+        assert!(entries.next_entry().unwrap().is_none());
+        assert_eq!(
+            [PathBuf::from("/b"), PathBuf::from("/c")].as_slice(),
+            entries.partial_files()
+        );
+        let damaged = [b_frames[3]..b_frames[4], c_frames[3]..cut]
+            .map(|range| range.start as u64..range.end as u64);
+        assert_eq!(damaged.as_slice(), entries.damaged_ranges());
+    }
+
+    #[test]
+    fn test_codecs() {
+        let compressions = [
+            Compression::Store,
+            Compression::Lz4,
+            Compression::Zstd { level: 3 },
+        ];
+        let text = "the quick brown fox jumps over the lazy dog\n".repeat(5000);
+        let mut arena = Arena::new();
+        let mut stream = Vec::new();
+
+        for (index, compression) in compressions.into_iter().enumerate() {
+            let codec = compression.codec_for(text.as_bytes());
+            let mut encoder = Encoder::new(codec, compression).unwrap();
+            let mut messages = vec![Message::FileHeader {
+                path: format!("/{index}").into(),
+                len: text.len() as u64,
+                codec,
+                metadata: EntryMetadata::default(),
+                atime_preserved: true,
+                mode: CollectionMode::Full,
+            }];
+
+            for chunk in text.as_bytes().chunks(CHUNK) {
+                messages.push(Message::FileBody {
+                    data: encoder.encode(Bytes::copy_from_slice(chunk)).unwrap(),
+                });
+            }
+
+            messages.push(Message::FileBody {
+                data: encoder.finish().unwrap(),
+            });
+            messages.push(Message::FileFooter {
+                digests: Vec::new(),
+            });
+
+            for message in &messages {
+                stream.extend_from_slice(&frame(message, &mut arena));
+            }
+        }
+
+        let mut entries = Entries::new(stream.as_slice());
+
+        for index in 0..compressions.len() {
+            let (path, contents, digests) = read_file(&mut entries);
+            assert_eq!(PathBuf::from(format!("/{index}")), path);
+            assert_eq!(text.as_bytes(), contents);
+            assert!(digests.is_some());
+        }
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        let mut stream = Vec::new();
+        file_frames("/a", b"hello", &mut stream);
+
+        // A frame that checks out, but whose payload is junk.
+        let payload = [0xff; 64];
+        let start = stream.len() as u64;
+        stream.extend_from_slice(&FRAME_MAGIC);
+        stream.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        stream.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        stream.extend_from_slice(&payload);
+        let end = stream.len() as u64;
+
+        file_frames("/b", b"world", &mut stream);
+
+        let mut entries = Entries::new(stream.as_slice());
+        let (path, contents, _) = read_file(&mut entries);
+        assert_eq!((PathBuf::from("/a"), b"hello".to_vec()), (path, contents));
+        let (path, contents, _) = read_file(&mut entries);
+        assert_eq!((PathBuf::from("/b"), b"world".to_vec()), (path, contents));
+        assert!(entries.next_entry().unwrap().is_none());
+
+        assert_eq!(&[Range { start, end }], entries.damaged_ranges());
+    }
+
+    /// A file's name, length, mode, contents and digests.
+    type CollectedFile = (String, u64, CollectionMode, Vec<u8>, Option<FileDigests>);
+
+    /// The stream a real collection of `root` sends.
+    fn collect(root: &Path, options: StreamOptions) -> Vec<u8> {
+        let stream: Vec<Bytes> = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(build_stream([root.to_owned()].into_iter(), options).try_collect())
+            .unwrap();
+
+        stream.concat()
+    }
+
+    /// Every file a real collection of `root` captured, by name.
+    fn collect_files(root: &Path, options: StreamOptions) -> Vec<CollectedFile> {
+        let stream = collect(root, options);
+        let mut entries = Entries::new(stream.as_slice());
+        let mut files = Vec::new();
+
+        while let Some(entry) = entries.next_entry().unwrap() {
+            if let EntryKind::File { len, mode, .. } = entry.kind {
+                let mut contents = Vec::new();
+                entries.contents().read_to_end(&mut contents).unwrap();
+                let digests = entries.finish().unwrap();
+
+                let name = entry.path.strip_prefix(root).unwrap();
+                let name = name.to_string_lossy().into_owned();
+                files.push((name, len, mode, contents, digests));
+            }
+        }
+
+        assert!(entries.partial_files().is_empty());
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        files
+    }
+
+    #[test]
+    fn test_collection_modes() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("etc")).unwrap();
+        fs::create_dir(root.path().join("usr")).unwrap();
+        fs::write(root.path().join("etc/passwd"), "root:x:0:0").unwrap();
+        fs::write(root.path().join("usr/ls"), "binary").unwrap();
+        fs::write(root.path().join("other"), "unread").unwrap();
+
+        let pattern = |rest| format!("{}/{rest}", root.path().display());
+        let rules = [
+            format!("full:{}", pattern("etc/**")),
+            format!("hash:{}", pattern("usr/**")),
+            "metadata".to_owned(),
+        ];
+        let options = StreamOptions {
+            modes: ModeRules::new(rules.iter().map(|rule| rule.parse().unwrap())).unwrap(),
+            ..Default::default()
+        };
+
+        let files: Vec<_> = collect_files(root.path(), options)
+            .into_iter()
+            .map(|(name, len, mode, contents, digests)| {
+                (name, len, mode, contents, digests.is_some())
+            })
+            .collect();
+        let expected = [
+            (
+                "etc/passwd",
+                10,
+                CollectionMode::Full,
+                &b"root:x:0:0"[..],
+                true,
+            ),
+            ("other", 6, CollectionMode::Metadata, b"", false),
+            ("usr/ls", 6, CollectionMode::Hash, b"", true),
+        ]
+        .map(|(name, len, mode, contents, hashed)| {
+            (name.to_owned(), len, mode, contents.to_vec(), hashed)
+        });
+
+        assert_eq!(expected.as_slice(), files);
+    }
+
+    #[test]
+    fn test_known_files() {
+        let root = tempfile::tempdir().unwrap();
+        let small = b"stock binary".to_vec();
+        // Too big to keep from the known-file check, so it's read twice.
+        let large = data(7, 3 * 1024 * 1024);
+
+        for (name, contents) in [
+            ("known-small", &small),
+            ("known-large", &large),
+            ("small", &small[1..].to_vec()),
+            ("large", &large[1..].to_vec()),
+        ] {
+            fs::write(root.path().join(name), contents).unwrap();
+        }
+
+        let sha1 = |data: &[u8]| {
+            let mut hashers = Hashers::new(&[DigestAlgorithm::Sha1]);
+            hashers.update(data);
+            hashers.finalize().remove(0)
+        };
+        let known = KnownHashes::new(
+            DigestAlgorithm::Sha1,
+            [sha1(&small).value, sha1(&large).value],
+        )
+        .unwrap();
+        let options = StreamOptions {
+            known: Some(Arc::new(known)),
+            ..Default::default()
+        };
+
+        let files = collect_files(root.path(), options.clone());
+        let modes: Vec<_> = files.iter().map(|file| (file.0.as_str(), file.2)).collect();
+        assert_eq!(
+            [
+                ("known-large", CollectionMode::Known),
+                ("known-small", CollectionMode::Known),
+                ("large", CollectionMode::Full),
+                ("small", CollectionMode::Full),
+            ],
+            modes.as_slice()
+        );
+
+        // Known files carry just the digest that matched, and the rest are
+        // sent as usual.
+        assert_eq!(vec![sha1(&large)], files[0].4.as_ref().unwrap().0);
+        assert!(files[0].3.is_empty());
+        assert_eq!(large[1..], files[2].3);
+        assert_eq!(small[1..], files[3].3);
+        assert_eq!(2, files[2].4.as_ref().unwrap().0.len());
+
+        // Hashing alone gives the same answer.
+        let options = StreamOptions {
+            modes: ModeRules::new(["hash".parse().unwrap()]).unwrap(),
+            ..options
+        };
+        let files = collect_files(root.path(), options);
+        assert_eq!(CollectionMode::Known, files[1].2);
+        assert_eq!(CollectionMode::Hash, files[3].2);
+        assert_eq!(
+            DigestAlgorithm::DEFAULT.len(),
+            files[3].4.as_ref().unwrap().0.len()
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        let root = tempfile::tempdir().unwrap();
+        // Random enough for the chunker to find cut points in.
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let shared: Vec<u8> = (0..2 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let edited = [b"a few bytes in front".as_slice(), &shared, &data(2, 1000)].concat();
+
+        fs::write(root.path().join("a"), &shared).unwrap();
+        fs::write(root.path().join("b"), &edited).unwrap();
+        fs::write(root.path().join("c"), &shared).unwrap();
+        fs::write(root.path().join("small"), "hello").unwrap();
+
+        let options = StreamOptions {
+            digests: vec![DigestAlgorithm::Sha256],
+            // So sizes come down to deduplication alone.
+            compression: Compression::Store,
+            concurrency: 8,
+            dedup: true,
+            ..Default::default()
+        };
+
+        let files = collect_files(root.path(), options.clone());
+        let contents = [&shared, &edited, &shared, &b"hello".to_vec()];
+
+        for ((name, _, _, actual, digests), expected) in files.iter().zip(contents) {
+            let mut hashers = Hashers::new(&[DigestAlgorithm::Sha256]);
+            hashers.update(expected);
+
+            assert_eq!(expected, actual, "{name}");
+            assert_eq!(hashers.finalize(), digests.as_ref().unwrap().0, "{name}");
+        }
+
+        // Files encoded at the same time may both send a chunk, but one at a
+        // time, the three copies go out about once.
+        let options = StreamOptions {
+            concurrency: 1,
+            ..options
+        };
+        let deduplicated = collect(root.path(), options.clone()).len();
+        let whole = collect(
+            root.path(),
+            StreamOptions {
+                dedup: false,
+                ..options
+            },
+        )
+        .len();
+        assert!(deduplicated < whole / 2, "{deduplicated} vs {whole}");
+
+        // A reference to a chunk that never arrived cuts the file short.
+        let mut arena = Arena::new();
+        let mut stream = Vec::new();
+        let messages = [
+            Message::FileHeader {
+                path: "/a".into(),
+                len: 5,
+                codec: Codec::Store,
+                metadata: EntryMetadata::default(),
+                atime_preserved: true,
+                mode: CollectionMode::Full,
+            },
+            Message::ChunkRef { hash: [1; 32] },
+            Message::FileFooter {
+                digests: Vec::new(),
+            },
+        ];
+
+        for message in &messages {
+            stream.extend_from_slice(&frame(message, &mut arena));
+        }
+
+        let mut entries = Entries::new(stream.as_slice());
+        let (_, contents, digests) = read_file(&mut entries);
+        assert!(contents.is_empty());
+        assert!(digests.is_none());
+        assert!(entries.next_entry().unwrap().is_none());
+        assert_eq!([PathBuf::from("/a")].as_slice(), entries.partial_files());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use agentfs::{
+    digest::{DigestAlgorithm, Hashers},
+    mode::CollectionMode,
+};
+
+use crate::reader::{Entries, EntryKind};
+
+/// How a captured file's contents compare to the digests the agent sent.
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub verified: usize,
+    /// Files whose contents don't match, along with the algorithms that
+    /// disagreed.
+    pub mismatched: Vec<(PathBuf, Vec<DigestAlgorithm>)>,
+    /// Files that were cut off, so have no digests to check against.
+    pub unverifiable: Vec<PathBuf>,
+    /// Files captured as metadata or digests alone, so with no contents to
+    /// check.
+    pub not_collected: usize,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched.is_empty()
+    }
+}
+
+/// Decompress every file and recompute the digests listed in its footer.
+///
+/// The algorithms come from the stream's header, so whichever set the
+/// collection used is checked. A stream whose header was lost is checked
+/// against everything the footers might hold.
+pub fn verify<R: Read>(entries: &mut Entries<R>) -> anyhow::Result<Verification> {
+    let mut verification = Verification::default();
+
+    while let Some(entry) = entries.next_entry()? {
+        let EntryKind::File { mode, .. } = entry.kind else {
+            continue;
+        };
+
+        if mode != CollectionMode::Full {
+            verification.not_collected += 1;
+            continue;
+        }
+
+        let algorithms = entries
+            .digest_algorithms()
+            .map_or(DigestAlgorithm::ALL.to_vec(), <[_]>::to_vec);
+        let mut hashers = HashingWriter(Hashers::new(&algorithms));
+
+        io::copy(&mut entries.contents(), &mut hashers)?;
+
+        let Some(expected) = entries.finish()? else {
+            verification.unverifiable.push(entry.path);
+            continue;
+        };
+
+        let actual = hashers.0.finalize();
+        let mismatched: Vec<_> = expected
+            .0
+            .iter()
+            .filter(|digest| !actual.contains(digest))
+            .map(|digest| digest.algorithm)
+            .collect();
+
+        if mismatched.is_empty() {
+            verification.verified += 1;
+        } else {
+            verification.mismatched.push((entry.path, mismatched));
+        }
+    }
+
+    Ok(verification)
+}
+
+struct HashingWriter(Hashers);
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use agentfs::{
+        codec::Codec,
+        stream::{EntryMetadata, Message, STREAM_VERSION, frame},
+    };
+    use lz4_flex::frame::FrameEncoder;
+    use rkyv::ser::allocator::Arena;
+
+    use super::*;
+
+    /// Frames for a file holding `data`, whose footer has the digests of
+    /// `hashed`.
+    fn file_frames(
+        path: &str,
+        data: &[u8],
+        hashed: &[u8],
+        algorithms: &[DigestAlgorithm],
+    ) -> Vec<u8> {
+        let mut arena = Arena::new();
+        let mut hashers = Hashers::new(algorithms);
+        hashers.update(hashed);
+
+        let mut encoder = FrameEncoder::new(Vec::new());
+        encoder.write_all(data).unwrap();
+
+        let messages = [
+            Message::FileHeader {
+                path: path.into(),
+                len: data.len() as u64,
+                codec: Codec::Lz4,
+                metadata: EntryMetadata::default(),
+                atime_preserved: true,
+                mode: CollectionMode::Full,
+            },
+            Message::FileBody {
+                data: encoder.finish().unwrap().into(),
+            },
+            Message::FileFooter {
+                digests: hashers.finalize(),
+            },
+        ];
+
+        messages
+            .iter()
+            .flat_map(|message| frame(message, &mut arena))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify() {
+        let algorithms = [DigestAlgorithm::Sha1, DigestAlgorithm::Blake3];
+        let header = Message::StreamHeader {
+            version: STREAM_VERSION,
+            digests: algorithms.to_vec(),
+            job: String::new(),
+        };
+
+        let mut stream = frame(&header, &mut Arena::new()).to_vec();
+        stream.extend(file_frames("/good", b"hello", b"hello", &algorithms));
+        stream.extend(file_frames("/bad", b"jello", b"hello", &algorithms));
+
+        let verification = verify(&mut Entries::new(stream.as_slice())).unwrap();
+        assert_eq!(1, verification.verified);
+        assert!(!verification.is_ok());
+        assert_eq!(
+            [(PathBuf::from("/bad"), algorithms.to_vec())].as_slice(),
+            verification.mismatched
+        );
+
+        // Without the header, whatever the footers hold is still checked.
+        let stream = file_frames("/good", b"hello", b"hello", &algorithms);
+        let verification = verify(&mut Entries::new(stream.as_slice())).unwrap();
+        assert_eq!(1, verification.verified);
+    }
+}
@@ -0,0 +1,357 @@
+//! Collecting from an agent served in-process, the way the decoder would
+//! from a real one.
+
+use std::{
+    fs,
+    io::{Cursor, Read},
+    net,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use agentfs::{
+    auth::{Auth, MIN_KEY_LEN, Scope, mint},
+    codec::Compression,
+    encryption::Identity,
+    signing::SigningKey,
+    stream::StreamOptions,
+    web_service,
+};
+use decoder::{
+    decrypt::decrypt,
+    input::open_url,
+    reader::{Entries, EntryKind},
+};
+use tokio::{net::TcpListener, runtime::Runtime};
+
+/// Serve `root` on a port of its own for as long as the tests run,
+/// returning the URL to collect it from.
+fn serve_agent(root: &Path, options: StreamOptions, auth: Auth) -> String {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let url = format!("http://{}/fs", listener.local_addr().unwrap());
+    let root = root.to_owned();
+
+    thread::spawn(move || {
+        Runtime::new().unwrap().block_on(async {
+            let listener = TcpListener::from_std(listener).unwrap();
+            web_service::serve(listener, [root], options, auth)
+                .await
+                .unwrap();
+        });
+    });
+
+    url
+}
+
+/// The status of a `method` request to `url` with `token`, and an empty
+/// JSON object for a body.
+fn status(method: &str, url: &str, token: &str) -> u16 {
+    let request = ureq::http::Request::builder()
+        .method(method)
+        .uri(url)
+        .header("Authorization", format!("Bearer {token}"))
+        .header("Content-Type", "application/json")
+        .body("{}")
+        .unwrap();
+
+    match ureq::run(request) {
+        Ok(response) => response.status().as_u16(),
+        Err(ureq::Error::StatusCode(status)) => status,
+        Err(err) => panic!("{err}"),
+    }
+}
+
+/// Every file in a stream, with its contents.
+fn files(stream: &[u8]) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut entries = Entries::new(stream);
+    let mut files = Vec::new();
+
+    while let Some(entry) = entries.next_entry().unwrap() {
+        let mut contents = Vec::new();
+        entries.contents().read_to_end(&mut contents).unwrap();
+        files.push((entry.path, contents));
+    }
+
+    assert!(entries.partial_files().is_empty());
+    assert!(entries.damaged_ranges().is_empty());
+
+    files
+}
+
+#[test]
+fn test_resume() {
+    let source = tempfile::tempdir().unwrap();
+
+    for file in 0..20 {
+        let contents: Vec<u8> = (0..300_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8 ^ file)
+            .collect();
+        fs::write(source.path().join(file.to_string()), contents).unwrap();
+    }
+
+    let options = StreamOptions {
+        compression: Compression::Store,
+        ..Default::default()
+    };
+    let url = serve_agent(source.path(), options, Auth::default());
+
+    // Only when asked for.
+    let response = ureq::get(&url).call().unwrap();
+    assert!(!response.headers().contains_key("x-job-id"));
+    let mut entries = Entries::new(response.into_body().into_reader());
+    while entries.next_entry().unwrap().is_some() {}
+    assert_eq!(None, entries.checkpoint());
+
+    let response = ureq::get(format!("{url}?resumable=true")).call().unwrap();
+    let job = response.headers()["x-job-id"].to_str().unwrap().to_owned();
+    let whole = response.into_body().read_to_vec().unwrap();
+
+    // The connection dropped part way through a file.
+    let cut = &whole[..whole.len() * 7 / 12];
+    let mut entries = Entries::new(cut);
+    while entries.next_entry().unwrap().is_some() {}
+
+    let checkpoint = entries.checkpoint().unwrap().clone();
+    assert_eq!(job, checkpoint.job);
+    assert!(!checkpoint.from.deleted);
+    assert!(checkpoint.from.path.starts_with(source.path()));
+    assert!(!entries.partial_files().is_empty());
+
+    // Something already sent going away doesn't move where the resumed
+    // stream picks up.
+    fs::remove_file(source.path().join("0")).unwrap();
+
+    let resumed = ureq::get(format!(
+        "{url}?resume={}&from={}",
+        checkpoint.job, checkpoint.from
+    ))
+    .call()
+    .unwrap()
+    .into_body()
+    .read_to_vec()
+    .unwrap();
+
+    let mut stream = cut[..checkpoint.offset as usize].to_vec();
+    stream.extend(resumed);
+    assert_eq!(files(&whole), files(&stream));
+
+    let unknown = ureq::get(format!("{url}?resume=nope")).call();
+    assert!(matches!(unknown, Err(ureq::Error::StatusCode(404))));
+
+    let garbled = ureq::get(format!("{url}?resume={job}&from=nope")).call();
+    assert!(matches!(garbled, Err(ureq::Error::StatusCode(400))));
+}
+
+#[test]
+fn test_encrypted() {
+    let source = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("shadow"), "root:*:19000:0:99999:7:::\n").unwrap();
+
+    let identity = Identity::generate();
+    let options = StreamOptions {
+        recipients: vec![identity.recipient()],
+        ..Default::default()
+    };
+    let url = serve_agent(source.path(), options, Auth::default());
+
+    let mut sealed = Vec::new();
+    open_url(&url, None, None, None)
+        .unwrap()
+        .read_to_end(&mut sealed)
+        .unwrap();
+    assert!(!sealed.windows(4).any(|window| window == b"root"));
+
+    let input = decrypt(Box::new(Cursor::new(sealed.clone())), Some(&identity)).unwrap();
+    let mut entries = Entries::new(input);
+    let mut shadow = String::new();
+
+    while let Some(entry) = entries.next_entry().unwrap() {
+        if entry.path.ends_with("shadow") {
+            entries.contents().read_to_string(&mut shadow).unwrap();
+        }
+    }
+
+    assert_eq!("root:*:19000:0:99999:7:::\n", shadow);
+    assert!(decrypt(Box::new(Cursor::new(sealed)), None).is_err());
+
+    // A cut-off encrypted capture can't be picked up where it stopped.
+    match ureq::get(format!("{url}?resumable=true")).call() {
+        Err(ureq::Error::StatusCode(status)) => assert_eq!(400, status),
+        other => panic!("resumable encrypted collection: {other:?}"),
+    }
+}
+
+#[test]
+fn test_signed() {
+    let source = tempfile::tempdir().unwrap();
+    fs::write(
+        source.path().join("auth.log"),
+        "Accepted publickey\n".repeat(1000),
+    )
+    .unwrap();
+
+    let key = SigningKey::generate();
+    let options = StreamOptions {
+        signing_key: Some(Arc::new(key.clone())),
+        ..Default::default()
+    };
+    let url = serve_agent(source.path(), options, Auth::default());
+
+    let mut stream = Vec::new();
+    open_url(&format!("{url}?modes=hash:**/*.log"), None, None, None)
+        .unwrap()
+        .read_to_end(&mut stream)
+        .unwrap();
+
+    let signature = |stream: &[u8]| {
+        let mut entries = Entries::new(stream);
+        while entries.next_entry().unwrap().is_some() {}
+
+        entries.signature()
+    };
+
+    let good = signature(&stream).unwrap();
+    assert!(good.valid);
+    assert_eq!(key.signer(), good.signer);
+    assert!(
+        good.parameters
+            .contains(r#""modes":["hash:**/*.log","full"]"#)
+    );
+
+    // Altered after the fact.
+    let mut altered = stream.clone();
+    let at = altered.len() / 2;
+    altered[at] ^= 1;
+    assert!(!signature(&altered).unwrap().valid);
+
+    // With something added after the trailer.
+    let mut appended = stream.clone();
+    appended.extend_from_slice(&stream[..100]);
+    assert!(!signature(&appended).unwrap().valid);
+
+    // Cut off before the trailer, it isn't signed at all.
+    assert_eq!(None, signature(&stream[..stream.len() - 200]));
+}
+
+#[test]
+fn test_resumed_signed() {
+    let source = tempfile::tempdir().unwrap();
+
+    for file in 0..10 {
+        let contents = format!("{file} Accepted publickey\n").repeat(20_000);
+        fs::write(source.path().join(file.to_string()), contents).unwrap();
+    }
+
+    let key = SigningKey::generate();
+    let options = StreamOptions {
+        compression: Compression::Store,
+        signing_key: Some(Arc::new(key.clone())),
+        ..Default::default()
+    };
+    let url = serve_agent(source.path(), options, Auth::default());
+
+    let response = ureq::get(format!("{url}?resumable=true")).call().unwrap();
+    let job = response.headers()["x-job-id"].to_str().unwrap().to_owned();
+    let whole = response.into_body().read_to_vec().unwrap();
+
+    let cut = &whole[..whole.len() / 2];
+    let mut entries = Entries::new(cut);
+    while entries.next_entry().unwrap().is_some() {}
+    assert_eq!(None, entries.signature());
+    let checkpoint = entries.checkpoint().unwrap().clone();
+
+    let resumed = ureq::get(format!("{url}?resume={job}&from={}", checkpoint.from))
+        .call()
+        .unwrap()
+        .into_body()
+        .read_to_vec()
+        .unwrap();
+
+    let mut stream = cut[..checkpoint.offset as usize].to_vec();
+    stream.extend(resumed);
+    assert_eq!(files(&whole), files(&stream));
+
+    // The attempt that finished is signed, from its own header onwards.
+    let mut entries = Entries::new(stream.as_slice());
+    while entries.next_entry().unwrap().is_some() {}
+    let signature = entries.signature().unwrap();
+    assert!(signature.valid);
+    assert_eq!(key.signer(), signature.signer);
+    assert_eq!(checkpoint.offset, signature.start);
+}
+
+#[test]
+fn test_auth() {
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("etc")).unwrap();
+    fs::create_dir(source.path().join("var")).unwrap();
+    fs::write(source.path().join("etc/passwd"), "root:x:0:0").unwrap();
+    fs::write(source.path().join("var/auth.log"), "Accepted publickey").unwrap();
+
+    let key = vec![7; MIN_KEY_LEN];
+    let tokens = "
+        analyst s3cret-analyst-token pattern:**/*.log
+        ops s3cret-ops-token
+    ";
+    let auth = Auth::new(Some(tokens), Some(key.clone())).unwrap();
+    let url = serve_agent(source.path(), StreamOptions::default(), auth);
+
+    let names = |token: &str| -> Vec<String> {
+        let stream = open_url(&url, Some(token), None, None).unwrap();
+        let mut entries = Entries::new(stream);
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().unwrap() {
+            if matches!(entry.kind, EntryKind::File { .. }) {
+                names.push(
+                    entry
+                        .path
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                );
+            }
+        }
+
+        names
+    };
+
+    assert!(open_url(&url, None, None, None).is_err());
+    assert!(open_url(&url, Some("guess"), None, None).is_err());
+
+    assert_eq!(["auth.log"], names("s3cret-analyst-token").as_slice());
+
+    let scope = Scope {
+        roots: vec![source.path().join("etc")],
+        ..Default::default()
+    };
+    let responder = mint(&key, "responder", Duration::from_secs(60), scope);
+    assert_eq!(["passwd"], names(&responder).as_slice());
+
+    // Only admins change what every collection shares.
+    let throttle = url.replace("/fs", "/throttle");
+    let known = url.replace("/fs", "/known");
+    for token in ["s3cret-analyst-token", &responder] {
+        assert_eq!(403, status("PUT", &throttle, token));
+        assert_eq!(403, status("PUT", &known, token));
+        assert_eq!(403, status("DELETE", &known, token));
+    }
+
+    let admin = mint(
+        &key,
+        "lead",
+        Duration::from_secs(60),
+        Scope {
+            roots: vec![source.path().join("etc")],
+            admin: true,
+            ..Default::default()
+        },
+    );
+    for token in ["s3cret-ops-token", &admin] {
+        assert_eq!(200, status("PUT", &throttle, token));
+        assert_eq!(204, status("DELETE", &known, token));
+    }
+}
@@ -4,18 +4,35 @@ use std::{
 };
 
 use anyhow::Ok;
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use globset::{Glob, GlobBuilder, GlobSetBuilder};
 use indexmap::IndexSet;
 use walkdir::{DirEntry, WalkDir};
 
 pub mod file_offline;
+pub mod parallel;
 pub mod parse_mounts;
+pub mod stream;
+
+pub use globset::GlobSet;
 
 #[derive(Debug)]
 pub struct RootIteratorPackage {
     globset: GlobSet,
     pattern_paths: Vec<(PathBuf, bool)>,
     root_paths: Vec<PathBuf>,
+    sorted: bool,
+}
+
+impl RootIteratorPackage {
+    /// Walk each directory's entries sorted by name, rather than in whatever
+    /// order the filesystem gives them, so that identical trees are walked
+    /// identically. Names are compared as `OsStr`s, regardless of locale:
+    /// byte-wise on Unix, and by their WTF-8 bytes on Windows. The parallel
+    /// walker ignores this, since it walks in no particular order anyway.
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.sorted = sorted;
+        self
+    }
 }
 
 // Ok past self, why do this root iterator package stuff?
@@ -39,7 +56,7 @@ pub struct RootIteratorPackage {
 //      - glob pattern minimization
 //          - if certain patterns are more generous in their matching, aka two patterns that share
 //              the same root, but one ends with `**` earlier in its path, discard all others.
-pub fn parse_patterns<IP, P>(patterns: IP)
+pub fn parse_patterns<IP, P>(_patterns: IP)
 where
     P: AsRef<str>,
     IP: IntoIterator<Item = P>,
@@ -58,10 +75,7 @@ where
 {
     let iter = patterns.into_iter().filter_map(|p| {
         let pattern = p.as_ref().trim();
-        let glob = GlobBuilder::new(pattern)
-            .literal_separator(true)
-            .build()
-            .ok()?;
+        let glob = glob(pattern).ok()?;
         let (root_path, match_all_recurse) = root_parser(pattern)?;
 
         Some((root_path.into(), glob, match_all_recurse))
@@ -86,12 +100,51 @@ where
         globset,
         pattern_paths: pattern_paths.into_iter().collect(),
         root_paths: roots.into_iter().map(|p| p.as_ref().to_owned()).collect(),
+        sorted: false,
     };
 
     Ok(package)
 }
 
-#[fauxgen::generator(yield = DirEntry)]
+/// A pattern matching everything underneath `root`.
+pub fn recursive_pattern(root: impl AsRef<Path>) -> String {
+    let root = root.as_ref().to_string_lossy();
+
+    format!("{}/**", globset::escape(root.trim_end_matches('/')))
+}
+
+/// Build a single glob with the same matching rules the root iterator uses,
+/// namely that `*` and `?` never match across a path separator.
+pub fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+    GlobBuilder::new(pattern.trim())
+        .literal_separator(true)
+        .build()
+}
+
+/// Build a set of globs using the same matching rules as the root iterator.
+///
+/// Unlike `root_iterator_package`, an invalid pattern is an error rather than
+/// being silently dropped.
+pub fn glob_set<IP, P>(patterns: IP) -> anyhow::Result<GlobSet>
+where
+    IP: IntoIterator<Item = P>,
+    P: AsRef<str>,
+{
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        builder.add(glob(pattern.as_ref())?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Walk the package's pattern roots, then the rest of its roots.
+///
+/// Errors reading a directory or an entry are yielded too, and the walk
+/// carries on past them, so that callers can tell an unreadable directory
+/// from an empty one.
+#[fauxgen::generator(yield = walkdir::Result<DirEntry>)]
 pub fn root_iterator(package: RootIteratorPackage) {
     let mut skip_paths = HashSet::new();
 
@@ -101,10 +154,9 @@ pub fn root_iterator(package: RootIteratorPackage) {
             skip_paths.insert(path.clone());
         }
 
-        let iter = WalkDir::new(path)
+        let iter = walk_dir(path, package.sorted)
             .into_iter()
-            .filter_map(Result::ok)
-            .filter(|entry| package.globset.is_match(entry.path()));
+            .filter(|entry| entry_path(entry).is_none_or(|path| package.globset.is_match(path)));
 
         for entry in iter {
             r#yield!(entry);
@@ -115,32 +167,14 @@ pub fn root_iterator(package: RootIteratorPackage) {
     // previously matched root patterns, and skip over any
     // directory that we know was wildcard matched already.
     for path in package.root_paths {
-        let iter = WalkDir::new(path)
+        let iter = walk_dir(path, package.sorted)
             .into_iter()
             .filter_entry(|entry| {
                 let path = entry.path();
 
-                #[cfg(any(target_os = "macos", target_os = "ios"))]
-                {
-                    if path == Path::new("/dev") {
-                        return false;
-                    }
-                }
-
-                #[cfg(any(target_os = "linux", target_os = "android"))]
-                {
-                    if path == Path::new("/dev")
-                        || path == Path::new("/proc")
-                        || path == Path::new("/sys")
-                    {
-                        return false;
-                    }
-                }
-
-                !skip_paths.contains(path)
+                !is_pseudo_filesystem(path) && !skip_paths.contains(path)
             })
-            .filter_map(Result::ok)
-            .filter(|entry| !package.globset.is_match(entry.path()));
+            .filter(|entry| entry_path(entry).is_none_or(|path| !package.globset.is_match(path)));
 
         for entry in iter {
             r#yield!(entry);
@@ -148,10 +182,33 @@ pub fn root_iterator(package: RootIteratorPackage) {
     }
 }
 
-struct RootParserData<'a> {
-    root: &'a Path,
-    recursive_match_all: bool,
-    remainder: &'a str,
+fn entry_path(entry: &walkdir::Result<DirEntry>) -> Option<&Path> {
+    entry
+        .as_ref()
+        .map_or_else(walkdir::Error::path, |entry| Some(entry.path()))
+}
+
+fn walk_dir(path: PathBuf, sorted: bool) -> WalkDir {
+    let walk = WalkDir::new(path);
+
+    if sorted {
+        walk.sort_by_file_name()
+    } else {
+        walk
+    }
+}
+
+/// Kernel-provided trees that aren't worth walking, and can hang a reader.
+fn is_pseudo_filesystem(path: &Path) -> bool {
+    let pseudo: &[&str] = if cfg!(any(target_os = "linux", target_os = "android")) {
+        &["/dev", "/proc", "/sys"]
+    } else if cfg!(any(target_os = "macos", target_os = "ios")) {
+        &["/dev"]
+    } else {
+        &[]
+    };
+
+    pseudo.iter().any(|pseudo| path == Path::new(pseudo))
 }
 
 fn root_parser(input: &str) -> Option<(&Path, bool)> {
@@ -204,6 +261,8 @@ fn is_recursive_match_all(input: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use std::{fs, pin::pin};
+
     use super::*;
 
     #[test]
@@ -270,4 +329,67 @@ mod tests {
         let actual = root_parser(input);
         assert_eq!(None, actual);
     }
+
+    #[test]
+    fn test_recursive_pattern() {
+        assert_eq!("/**", recursive_pattern("/"));
+        assert_eq!("/var/log/**", recursive_pattern("/var/log/"));
+        assert_eq!("/tmp/[*]/**", recursive_pattern("/tmp/*"));
+
+        let pattern = recursive_pattern("/tmp/x");
+        assert_eq!(Some((Path::new("/tmp/x/"), true)), root_parser(&pattern));
+    }
+
+    #[test]
+    fn test_sorted() {
+        let names = ["b", "B", "a", "_x", "Z", "\u{e9}", "a.txt", "a0", "10", "9"];
+
+        // The same tree, built in opposite orders.
+        let forward = tempfile::tempdir().unwrap();
+        let backward = tempfile::tempdir().unwrap();
+
+        let build = |root: &Path, names: &mut dyn Iterator<Item = &&str>| {
+            for name in names {
+                fs::create_dir_all(root.join("dir")).unwrap();
+                fs::write(root.join(name), name).unwrap();
+                fs::write(root.join("dir").join(name), name).unwrap();
+            }
+        };
+        build(forward.path(), &mut names.iter());
+        build(backward.path(), &mut names.iter().rev());
+
+        let walk = |root: &Path, patterns: &[String]| -> Vec<PathBuf> {
+            let package = root_iterator_package([root], patterns)
+                .unwrap()
+                .sorted(true);
+
+            pin!(root_iterator(package))
+                .map(|entry| entry.unwrap().path().strip_prefix(root).unwrap().to_owned())
+                .collect()
+        };
+
+        let sequence = walk(forward.path(), &[]);
+        assert_eq!(sequence, walk(backward.path(), &[]));
+        assert_eq!(2 * names.len() + 2, sequence.len());
+
+        // Byte-wise, so upper case before lower case and non-ASCII last.
+        let top: Vec<_> = sequence
+            .iter()
+            .filter(|path| path.components().count() == 1)
+            .map(|path| path.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "10", "9", "B", "Z", "_x", "a", "a.txt", "a0", "b", "dir", "\u{e9}"
+            ],
+            top
+        );
+
+        // Patterns are walked first, in the order given, each sorted too.
+        let patterns = |root: &Path| vec![recursive_pattern(root.join("dir"))];
+        let sequence = walk(forward.path(), &patterns(forward.path()));
+        assert_eq!(sequence, walk(backward.path(), &patterns(backward.path())));
+        assert_eq!(Path::new("dir"), sequence[0]);
+        assert_eq!(Path::new("dir/10"), sequence[1]);
+    }
 }
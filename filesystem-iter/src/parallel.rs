@@ -0,0 +1,269 @@
+//! A multi-threaded alternative to `root_iterator`, for trees too big to walk
+//! one directory at a time.
+//!
+//! It finds the same entries as the sequential walker, but in no particular
+//! order, and hands them over through a channel as the walker threads find
+//! them.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+};
+
+use ignore::{WalkBuilder, WalkState};
+
+pub use ignore::DirEntry;
+
+use crate::{RootIteratorPackage, is_pseudo_filesystem};
+
+/// How many entries can be waiting in the channel before the walker threads
+/// block.
+const CHANNEL_BOUND: usize = 4096;
+
+/// Entries found by the walker threads. Dropping this stops the walk.
+pub struct RootIterator {
+    receiver: Receiver<DirEntry>,
+}
+
+impl Iterator for RootIterator {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Walk everything `root_iterator` would on `threads` threads, where `0`
+/// picks a number based on the CPU count.
+pub fn root_iterator(package: RootIteratorPackage, threads: usize) -> RootIterator {
+    let (sender, receiver) = mpsc::sync_channel(CHANNEL_BOUND);
+
+    thread::Builder::new()
+        .name("parallel-walk".into())
+        .spawn(move || walk(package, threads, sender))
+        .expect("failed to spawn the walker thread");
+
+    RootIterator { receiver }
+}
+
+fn walk(package: RootIteratorPackage, threads: usize, sender: SyncSender<DirEntry>) {
+    let globset = package.globset;
+
+    // The roots parsed from the glob patterns, keeping what matches.
+    let pattern_paths = package.pattern_paths.iter().map(|(path, _)| path);
+    let finished = run(pattern_paths, threads, None, &sender, |entry| {
+        globset.is_match(entry.path())
+    });
+
+    if !finished {
+        return;
+    }
+
+    // The root paths, keeping what the patterns don't match, and skipping
+    // any directory that was wildcard matched already.
+    let skip_paths: HashSet<PathBuf> = package
+        .pattern_paths
+        .into_iter()
+        .filter_map(|(path, recursive_match_all)| recursive_match_all.then_some(path))
+        .collect();
+    let keep =
+        Arc::new(move |path: &Path| !is_pseudo_filesystem(path) && !skip_paths.contains(path));
+
+    // The walker only filters what it finds underneath its roots.
+    let roots = package.root_paths.iter().filter(|path| keep(path));
+    let filter = keep.clone();
+
+    run(roots, threads, Some(filter), &sender, |entry| {
+        !globset.is_match(entry.path())
+    });
+}
+
+type Filter = Arc<dyn Fn(&Path) -> bool + Send + Sync>;
+
+/// Walk `roots` in parallel, sending on every entry `select` picks. Returns
+/// `false` if the receiver has gone away.
+fn run<'a>(
+    roots: impl IntoIterator<Item = &'a PathBuf>,
+    threads: usize,
+    filter: Option<Filter>,
+    sender: &SyncSender<DirEntry>,
+    select: impl Fn(&DirEntry) -> bool + Sync,
+) -> bool {
+    let mut roots = roots.into_iter();
+
+    let Some(first) = roots.next() else {
+        return true;
+    };
+
+    let mut builder = WalkBuilder::new(first);
+
+    for root in roots {
+        builder.add(root);
+    }
+
+    builder.standard_filters(false).threads(threads);
+
+    if let Some(filter) = filter {
+        builder.filter_entry(move |entry| filter(entry.path()));
+    }
+
+    let hung_up = AtomicBool::new(false);
+
+    builder.build_parallel().run(|| {
+        let sender = sender.clone();
+        let (hung_up, select) = (&hung_up, &select);
+
+        Box::new(move |result| {
+            let Ok(entry) = result else {
+                return WalkState::Continue;
+            };
+
+            if select(&entry) && sender.send(entry).is_err() {
+                hung_up.store(true, Ordering::Relaxed);
+                return WalkState::Quit;
+            }
+
+            WalkState::Continue
+        })
+    });
+
+    !hung_up.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, pin::pin};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{recursive_pattern, root_iterator_package};
+
+    /// a/{x.txt,y.log,deep/z.txt}, b/{1..50}/file, c -> a, top.txt
+    fn tree() -> TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path();
+
+        fs::create_dir_all(path.join("a/deep")).unwrap();
+        fs::write(path.join("a/x.txt"), "x").unwrap();
+        fs::write(path.join("a/y.log"), "y").unwrap();
+        fs::write(path.join("a/deep/z.txt"), "z").unwrap();
+        fs::write(path.join("top.txt"), "top").unwrap();
+
+        for dir in 0..50 {
+            let dir = path.join(format!("b/{dir}"));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("file"), "").unwrap();
+        }
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(path.join("a"), path.join("c")).unwrap();
+
+        root
+    }
+
+    /// Both walkers over the same roots and patterns, as sorted paths.
+    fn walk_both(roots: &[&Path], patterns: &[String]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let package = || root_iterator_package(roots, patterns).unwrap();
+
+        let mut sequential: Vec<_> = pin!(crate::root_iterator(package()))
+            .filter_map(Result::ok)
+            .map(|entry| entry.into_path())
+            .collect();
+        let mut parallel: Vec<_> = root_iterator(package(), 4)
+            .map(|entry| entry.into_path())
+            .collect();
+
+        sequential.sort();
+        parallel.sort();
+
+        (sequential, parallel)
+    }
+
+    fn assert_equivalent(roots: &[&Path], patterns: &[String]) -> Vec<PathBuf> {
+        let (sequential, parallel) = walk_both(roots, patterns);
+        assert_eq!(
+            sequential, parallel,
+            "roots {roots:?}, patterns {patterns:?}"
+        );
+
+        sequential
+    }
+
+    fn pattern(root: &Path, rest: &str) -> String {
+        format!("{}/{rest}", globset::escape(&root.to_string_lossy()))
+    }
+
+    #[test]
+    fn test_recursive_roots() {
+        let tree = tree();
+        let roots = [tree.path()];
+        let patterns = [recursive_pattern(tree.path())];
+
+        // The root, a and its four entries, top.txt, b with its 50
+        // directories and their files, and c.
+        let found = assert_equivalent(&roots, &patterns);
+        assert_eq!(1 + 5 + 1 + 101 + 1, found.len());
+    }
+
+    #[test]
+    fn test_partial_patterns() {
+        let tree = tree();
+        let roots = [tree.path()];
+
+        assert_equivalent(&roots, &[pattern(tree.path(), "a/*.txt")]);
+        assert_equivalent(&roots, &[pattern(tree.path(), "**/*.txt")]);
+        assert_equivalent(&roots, &[pattern(tree.path(), "b/*/file")]);
+        assert_equivalent(
+            &roots,
+            &[
+                pattern(tree.path(), "a/**"),
+                pattern(tree.path(), "b/1?/*"),
+                pattern(tree.path(), "{top,missing}.txt"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_skipped_directories() {
+        let tree = tree();
+        let roots = [tree.path()];
+
+        // Everything underneath b is found by the first pass, and skipped by
+        // the second.
+        let patterns = [recursive_pattern(tree.path().join("b"))];
+        let found = assert_equivalent(&roots, &patterns);
+        let file = tree.path().join("b/7/file");
+        assert_eq!(1, found.iter().filter(|path| **path == file).count());
+    }
+
+    #[test]
+    fn test_odd_roots() {
+        let tree = tree();
+        let file = tree.path().join("top.txt");
+        let missing = tree.path().join("missing");
+        let nested = tree.path().join("a");
+
+        assert_equivalent(&[&file], &[]);
+        assert_equivalent(&[&missing], &[recursive_pattern(&missing)]);
+        assert_equivalent(&[tree.path(), &nested], &[]);
+        assert_equivalent(&[], &[recursive_pattern(&nested)]);
+        assert_equivalent(&[&file], &[pattern(tree.path(), "*.txt")]);
+    }
+
+    #[test]
+    fn test_stop_early() {
+        let tree = tree();
+        let package = root_iterator_package([tree.path()], [] as [&str; 0]).unwrap();
+
+        // Dropping the iterator part way lets the walker threads finish.
+        let taken = root_iterator(package, 4).take(3).count();
+        assert_eq!(3, taken);
+    }
+}
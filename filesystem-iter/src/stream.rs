@@ -0,0 +1,181 @@
+//! An async view of `root_iterator`, for callers that mustn't block.
+
+use std::{
+    error::Error,
+    fmt,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    pin::{Pin, pin},
+    task::{Context, Poll},
+    thread,
+};
+
+use futures::{
+    SinkExt, Stream,
+    channel::mpsc::{self, Receiver},
+    executor::block_on,
+};
+
+use crate::{RootIteratorPackage, root_iterator};
+
+/// How many entries the walker may get ahead of whoever is consuming them.
+const BUFFER: usize = 256;
+
+/// A walked path along with its metadata, following symlinks.
+#[derive(Debug)]
+pub struct Entry {
+    path: PathBuf,
+    metadata: Metadata,
+}
+
+impl Entry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+}
+
+/// A path the walk couldn't read, either a directory's listing or an
+/// entry's metadata.
+#[derive(Debug)]
+pub struct WalkError {
+    path: Option<PathBuf>,
+    error: io::Error,
+}
+
+impl WalkError {
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn io_error(&self) -> &io::Error {
+        &self.error
+    }
+}
+
+impl From<walkdir::Error> for WalkError {
+    fn from(err: walkdir::Error) -> Self {
+        let path = err.path().map(Path::to_owned);
+        let error = err
+            .into_io_error()
+            .unwrap_or_else(|| io::Error::other("filesystem loop"));
+
+        WalkError { path, error }
+    }
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}: {}", path.display(), self.error),
+            None => self.error.fmt(f),
+        }
+    }
+}
+
+impl Error for WalkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Entries found by `root_stream`. Dropping this stops the walk.
+pub struct RootStream {
+    receiver: Receiver<Result<Entry, WalkError>>,
+}
+
+impl Stream for RootStream {
+    type Item = Result<Entry, WalkError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Walk the same entries as `root_iterator`, in the same order, on a thread
+/// of its own so that reading directories and metadata never blocks the
+/// caller. The walker waits whenever it gets too far ahead.
+///
+/// A directory that can't be listed, or an entry whose metadata can't be
+/// read, is an error naming that path, and the walk carries on past it.
+pub fn root_stream(package: RootIteratorPackage) -> RootStream {
+    let (mut sender, receiver) = mpsc::channel(BUFFER);
+
+    thread::Builder::new()
+        .name("root-stream".into())
+        .spawn(move || {
+            for entry in pin!(root_iterator(package)) {
+                let entry = entry.map_err(WalkError::from).and_then(|entry| {
+                    let path = entry.into_path();
+
+                    match path.metadata() {
+                        Ok(metadata) => Ok(Entry { path, metadata }),
+                        Err(error) => Err(WalkError {
+                            path: Some(path),
+                            error,
+                        }),
+                    }
+                });
+
+                if block_on(sender.send(entry)).is_err() {
+                    // Nobody is listening any more.
+                    break;
+                }
+            }
+        })
+        .expect("failed to spawn the walker thread");
+
+    RootStream { receiver }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::{StreamExt, TryStreamExt};
+
+    use super::*;
+    use crate::{recursive_pattern, root_iterator_package};
+
+    #[test]
+    fn test_root_stream() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir/file"), "contents").unwrap();
+
+        for file in 0..BUFFER * 2 {
+            fs::write(root.path().join(file.to_string()), "").unwrap();
+        }
+
+        let package =
+            || root_iterator_package([root.path()], [recursive_pattern(root.path())]).unwrap();
+
+        let expected: Vec<_> = pin!(root_iterator(package()))
+            .map(|entry| entry.unwrap().into_path())
+            .collect();
+        let entries: Vec<_> = block_on(root_stream(package()).try_collect()).unwrap();
+        let paths: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.path().to_owned())
+            .collect();
+        assert_eq!(expected, paths);
+
+        let file = entries
+            .iter()
+            .find(|entry| entry.path().ends_with("dir/file"))
+            .unwrap();
+        assert_eq!(8, file.metadata().len());
+
+        // Stopping early leaves the walker to wind itself down.
+        let taken = block_on(root_stream(package()).take(3).count());
+        assert_eq!(3, taken);
+    }
+}